@@ -0,0 +1,54 @@
+//! Random `get` throughput against a committed, on-disk tree.
+//!
+//! Run with `cargo bench --bench random_get`
+extern crate urkel_trie;
+
+use std::fs;
+use std::time::Instant;
+use urkel_trie::trie::UrkelTrie;
+
+const DIR: &str = "data-bench";
+const NUM_KEYS: u64 = 20_000;
+const NUM_GETS: u64 = 100_000;
+
+fn key(i: u64) -> Vec<u8> {
+    format!("name-{}", i).into_bytes()
+}
+
+fn main() {
+    let _ = fs::remove_dir_all(DIR);
+    {
+        let mut tree = UrkelTrie::new(DIR);
+        for i in 0..NUM_KEYS {
+            tree.set(&key(i), format!("value-{}", i)).unwrap();
+        }
+        tree.commit().unwrap();
+    }
+
+    // Reopen so every lookup starts from the root hash node on disk
+    let tree = UrkelTrie::new(DIR);
+
+    // Simple LCG so runs are repeatable without pulling in 'rand'
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let start = Instant::now();
+    for _ in 0..NUM_GETS {
+        seed = seed
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        let i = (seed >> 33) % NUM_KEYS;
+        assert!(tree.get(&key(i)).unwrap().is_some());
+    }
+    let elapsed = start.elapsed();
+
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    println!(
+        "random_get: {} gets over {} keys in {:.3}s ({:.0} gets/sec)",
+        NUM_GETS,
+        NUM_KEYS,
+        secs,
+        NUM_GETS as f64 / secs
+    );
+
+    drop(tree);
+    fs::remove_dir_all(DIR).expect("Should have deleted bench dir");
+}
@@ -0,0 +1,96 @@
+use super::node::Node;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Hit/miss counters for the store's cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+enum Cached {
+    Node(Node),
+    Value(Vec<u8>),
+}
+
+/// Size bounded LRU cache of decoded nodes (and optionally leaf values)
+/// keyed by their storage location (index, pos). Every operation starts
+/// at the root, so the upper levels of the trie stay cached.
+pub struct Cache {
+    entries: Mutex<LruCache<(u16, u32), Cached>>,
+    cache_values: bool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Cache {
+    /// Returns None if 'size' is 0 (caching disabled)
+    pub fn new(size: usize, cache_values: bool) -> Option<Cache> {
+        NonZeroUsize::new(size).map(|cap| Cache {
+            entries: Mutex::new(LruCache::new(cap)),
+            cache_values,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    pub fn get_node(&self, index: u16, pos: u32) -> Option<Node> {
+        let found = match self.entries.lock().unwrap().get(&(index, pos)) {
+            Some(Cached::Node(n)) => Some(n.clone()),
+            _ => None,
+        };
+        self.record(found.is_some());
+        found
+    }
+
+    /// Cache a node. A leaf read with its value only keeps it if values are
+    /// cached.
+    pub fn put_node(&self, index: u16, pos: u32, node: &Node) {
+        let mut node = node.clone();
+        if let Node::Leaf { ref mut value, .. } = node {
+            if !self.cache_values {
+                *value = None;
+            }
+        }
+        self.entries
+            .lock()
+            .unwrap()
+            .put((index, pos), Cached::Node(node));
+    }
+
+    pub fn get_value(&self, index: u16, pos: u32) -> Option<Vec<u8>> {
+        if !self.cache_values {
+            return None;
+        }
+        let found = match self.entries.lock().unwrap().get(&(index, pos)) {
+            Some(Cached::Value(v)) => Some(v.clone()),
+            _ => None,
+        };
+        self.record(found.is_some());
+        found
+    }
+
+    pub fn put_value(&self, index: u16, pos: u32, value: &[u8]) {
+        if self.cache_values {
+            self.entries
+                .lock()
+                .unwrap()
+                .put((index, pos), Cached::Value(value.to_vec()));
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,121 @@
+//! Value deduplication for `Store`.
+//!
+//! With `Options::dedup_values`, the store keeps an index from the hash of
+//! each value it wrote to where the value is. A new leaf whose value is
+//! already on disk points at the existing copy instead of writing another
+//! one. Leaves don't own their values, so any number of them can share one.
+//!
+//! The index is kept in memory (44 bytes per distinct value) and appended
+//! to a file next to the log at each commit. The file is only a hint: on
+//! open, records for values past the end of the log (dropped by crash
+//! recovery) are dropped too, and a lost record only costs a duplicate.
+//!
+//! The log is append only and nothing is ever removed from it, so a shared
+//! value stays readable as long as any root refers to it. Anything that
+//! rewrites the log has to copy values by the leaves that refer to them,
+//! not by this index.
+//!
+//! With encryption on, values are hashed with a key derived from the store's
+//! so the index doesn't give away their plain hashes.
+use blake2_rfc::blake2b::blake2b;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+// hash (32) + index (2) + pos (4) + size (2) + flags (1)
+const RECORD_SIZE: usize = 41;
+const COMPRESSED: u8 = 1;
+
+/// Where a value is stored, as recorded in its leaves
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ValueLocation {
+    pub index: u16,
+    pub pos: u32,
+    pub size: u16,
+    pub compressed: bool,
+}
+
+pub(crate) struct ValueIndex {
+    file: File,
+    key: Vec<u8>,
+    locations: HashMap<[u8; 32], ValueLocation>,
+    // Records for values written since the last commit
+    pending: Vec<u8>,
+}
+
+impl ValueIndex {
+    /// Open (or create) the index for a log that ends at 'end'. Records for
+    /// values past the end are dropped. 'key' is empty unless the store is
+    /// encrypted.
+    pub(crate) fn open(path: &Path, end: u64, key: &[u8]) -> io::Result<ValueIndex> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut bits = Vec::new();
+        file.read_to_end(&mut bits)?;
+
+        // Values are recorded in the order they were written, so everything
+        // after the first record past the end is past it too
+        let mut locations = HashMap::new();
+        let mut valid = 0;
+        for record in bits.chunks_exact(RECORD_SIZE) {
+            let location = ValueLocation {
+                index: LittleEndian::read_u16(&record[32..34]),
+                pos: LittleEndian::read_u32(&record[34..38]),
+                size: LittleEndian::read_u16(&record[38..40]),
+                compressed: record[40] & COMPRESSED != 0,
+            };
+            if u64::from(location.pos) + u64::from(location.size) > end {
+                break;
+            }
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(&record[..32]);
+            locations.insert(hash, location);
+            valid += RECORD_SIZE;
+        }
+        if valid < bits.len() {
+            file.set_len(valid as u64)?;
+        }
+
+        Ok(ValueIndex {
+            file,
+            key: key.to_vec(),
+            locations,
+            pending: Vec::new(),
+        })
+    }
+
+    pub(crate) fn hash(&self, value: &[u8]) -> [u8; 32] {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(blake2b(32, &self.key, value).as_bytes());
+        hash
+    }
+
+    pub(crate) fn get(&self, hash: &[u8; 32]) -> Option<ValueLocation> {
+        self.locations.get(hash).copied()
+    }
+
+    /// Record a value just written. It's shared right away; the record goes
+    /// to the file with the next commit.
+    pub(crate) fn insert(&mut self, hash: [u8; 32], location: ValueLocation) -> io::Result<()> {
+        self.pending.extend_from_slice(&hash);
+        self.pending.write_u16::<LittleEndian>(location.index)?;
+        self.pending.write_u32::<LittleEndian>(location.pos)?;
+        self.pending.write_u16::<LittleEndian>(location.size)?;
+        self.pending
+            .write_u8(if location.compressed { COMPRESSED } else { 0 })?;
+        self.locations.insert(hash, location);
+        Ok(())
+    }
+
+    /// Write out the records for the commit just made
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.file.write_all(&self.pending)?;
+        self.pending.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+//! Changes between two roots of a tree.
+use super::errors::Result;
+use super::has_bit;
+use super::hasher::Digest;
+use super::node::Node;
+use super::trie::UrkelTrie;
+use super::TrieStore;
+use std::collections::VecDeque;
+
+/// A difference between two roots. Keys are the hashed keys the tree
+/// stores, as the original keys aren't kept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Added(Digest, Vec<u8>),
+    Removed(Digest),
+    /// Key, old value, new value
+    Changed(Digest, Vec<u8>, Vec<u8>),
+}
+
+/// Iterator over the changes between two roots, in key order. Both trees are
+/// walked together and subtrees with the same hash are skipped, so only the
+/// parts that differ are read from the store. Created with `UrkelTrie::diff`.
+pub struct Diff<'a, S: TrieStore> {
+    trie: &'a UrkelTrie<S>,
+    // (old, new, depth) subtrees still to compare. The next one is last.
+    stack: Vec<(Node, Node, usize)>,
+    // Changes found but not returned yet
+    ready: VecDeque<Change>,
+}
+
+impl<'a, S: TrieStore> Diff<'a, S> {
+    pub(crate) fn new(trie: &'a UrkelTrie<S>, old: Node, new: Node) -> Self {
+        Diff {
+            trie,
+            stack: vec![(old, new, 0)],
+            ready: VecDeque::new(),
+        }
+    }
+
+    fn compare(&mut self, old: Node, new: Node, depth: usize) -> Result<()> {
+        if old.hash() == new.hash() {
+            return Ok(());
+        }
+        match (self.expand(old)?, self.expand(new)?) {
+            (
+                Node::Internal {
+                    left: old_left,
+                    right: old_right,
+                    ..
+                },
+                Node::Internal {
+                    left: new_left,
+                    right: new_right,
+                    ..
+                },
+            ) => {
+                self.stack.push((*old_right, *new_right, depth + 1));
+                self.stack.push((*old_left, *new_left, depth + 1));
+            }
+            (Node::Internal { left, right, .. }, new) => {
+                let (new_left, new_right) = split(new, depth);
+                self.stack.push((*right, new_right, depth + 1));
+                self.stack.push((*left, new_left, depth + 1));
+            }
+            (old, Node::Internal { left, right, .. }) => {
+                let (old_left, old_right) = split(old, depth);
+                self.stack.push((old_right, *right, depth + 1));
+                self.stack.push((old_left, *left, depth + 1));
+            }
+            (old, new) => self.compare_leaves(old, new)?,
+        }
+        Ok(())
+    }
+
+    /// Compare two leaves (or empty nodes)
+    fn compare_leaves(&mut self, old: Node, new: Node) -> Result<()> {
+        match (leaf_key(&old), leaf_key(&new)) {
+            (None, None) => {}
+            (None, Some(key)) => {
+                let value = self.trie.leaf_value(&new)?;
+                self.ready.push_back(Change::Added(key, value));
+            }
+            (Some(key), None) => self.ready.push_back(Change::Removed(key)),
+            (Some(old_key), Some(new_key)) if old_key == new_key => {
+                let old_value = self.trie.leaf_value(&old)?;
+                let new_value = self.trie.leaf_value(&new)?;
+                self.ready
+                    .push_back(Change::Changed(new_key, old_value, new_value));
+            }
+            (Some(old_key), Some(new_key)) => {
+                let added = Change::Added(new_key, self.trie.leaf_value(&new)?);
+                let removed = Change::Removed(old_key);
+                if old_key.0 < new_key.0 {
+                    self.ready.extend(vec![removed, added]);
+                } else {
+                    self.ready.extend(vec![added, removed]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn expand(&self, node: Node) -> Result<Node> {
+        match node {
+            Node::Hash { .. } => Ok(*self.trie.resolve(node)?),
+            n => Ok(n),
+        }
+    }
+}
+
+impl<'a, S: TrieStore> Iterator for Diff<'a, S> {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Result<Change>> {
+        loop {
+            if let Some(change) = self.ready.pop_front() {
+                return Some(Ok(change));
+            }
+            let (old, new, depth) = self.stack.pop()?;
+            if let Err(e) = self.compare(old, new, depth) {
+                self.stack.clear();
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+fn leaf_key(node: &Node) -> Option<Digest> {
+    match node {
+        Node::Leaf { key, .. } => Some(*key),
+        _ => None,
+    }
+}
+
+/// Put a leaf (or empty node) on the side of an internal node it would be
+/// on, with an empty node on the other side
+fn split(node: Node, depth: usize) -> (Node, Node) {
+    match leaf_key(&node) {
+        Some(key) if has_bit(&key, depth) => (Node::empty(), node),
+        Some(_) => (node, Node::empty()),
+        None => (Node::empty(), Node::empty()),
+    }
+}
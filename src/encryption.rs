@@ -0,0 +1,111 @@
+//! Encryption at rest for `Store`.
+//!
+//! Leaf values, and optionally node records, are sealed with
+//! XChaCha20-Poly1305 as they're written to the log. Hashing happens before
+//! anything is persisted, so root hashes and proofs are the same as for a
+//! plaintext store. Metas, commit metadata and the journal are not
+//! encrypted: they hold storage locations, hashed keys and whatever the
+//! caller passed to `commit_with`.
+//!
+//! Each sealed record is a random 24 byte nonce, then the ciphertext and its
+//! tag. The record's location in the log, `(index, pos)`, is authenticated
+//! along with it, so a record copied elsewhere in the log doesn't open.
+//! Random nonces that long don't repeat in practice, even when a crash
+//! leaves part of a commit on disk and the next one is written over it, or
+//! when several stores share a key.
+//!
+//! A store must always be opened with the settings it was written with.
+//! Opening it with the wrong key fails; opening it without its key reads
+//! garbage.
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use std::fmt;
+use std::io;
+
+// Size of the nonce stored in front of each sealed record
+const NONCE_SIZE: usize = 24;
+// Size of the authentication tag added to each sealed record
+const TAG_SIZE: usize = 16;
+/// Bytes sealing adds to a record
+pub(crate) const SEAL_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+/// Key and settings for encrypting a store. See the module docs.
+#[derive(Clone)]
+pub struct Encryption {
+    /// 256 bit key
+    pub key: [u8; 32],
+    /// Encrypt node records as well as values. This hides the shape of the
+    /// trie and the hashed keys, and adds 40 bytes to every node.
+    pub nodes: bool,
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption")
+            .field("key", &"..")
+            .field("nodes", &self.nodes)
+            .finish()
+    }
+}
+
+pub(crate) struct Cipher {
+    aead: XChaCha20Poly1305,
+    nodes: bool,
+}
+
+impl Cipher {
+    pub(crate) fn new(encryption: &Encryption) -> Cipher {
+        Cipher {
+            aead: XChaCha20Poly1305::new(Key::from_slice(&encryption.key)),
+            nodes: encryption.nodes,
+        }
+    }
+
+    pub(crate) fn encrypts_nodes(&self) -> bool {
+        self.nodes
+    }
+
+    /// Encrypt the record to be written at (index, pos)
+    pub(crate) fn seal(&self, index: u16, pos: u32, bits: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: bits,
+            aad: &location(index, pos),
+        };
+        let sealed = self
+            .aead
+            .encrypt(&nonce, payload)
+            .map_err(|_| io::Error::other("Encryption failed"))?;
+        let mut record = Vec::with_capacity(NONCE_SIZE + sealed.len());
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&sealed);
+        Ok(record)
+    }
+
+    /// Decrypt and authenticate the record read from (index, pos)
+    pub(crate) fn open(&self, index: u16, pos: u32, bits: &[u8]) -> io::Result<Vec<u8>> {
+        let opened = if bits.len() < SEAL_OVERHEAD {
+            None
+        } else {
+            let (nonce, sealed) = bits.split_at(NONCE_SIZE);
+            let payload = Payload {
+                msg: sealed,
+                aad: &location(index, pos),
+            };
+            self.aead.decrypt(XNonce::from_slice(nonce), payload).ok()
+        };
+        opened.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Can't decrypt record at {}:{} (wrong key?)", index, pos),
+            )
+        })
+    }
+}
+
+fn location(index: u16, pos: u32) -> [u8; 6] {
+    let mut bits = [0u8; 6];
+    bits[..2].copy_from_slice(&index.to_le_bytes());
+    bits[2..].copy_from_slice(&pos.to_le_bytes());
+    bits
+}
@@ -1,8 +1,10 @@
+use crate::hasher::Digest;
 use std::convert::From;
 use std::error;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::io;
+use std::path::PathBuf;
 use std::result;
 
 #[derive(Debug)]
@@ -10,6 +12,11 @@ pub enum Error {
     Io(io::Error),
     MetaRootNotFound,
     NoLogFiles,
+    ReadOnly,
+    Locked(PathBuf),
+    ReadersOpen(PathBuf),
+    UnknownRoot(Digest),
+    NoJournal(Digest),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -20,6 +27,19 @@ impl Display for Error {
             Error::Io(ref err) => write!(f, "IO error: {}", err),
             Error::MetaRootNotFound => write!(f, "Meta root not found"),
             Error::NoLogFiles => write!(f, "No logfiles in the current directory"),
+            Error::ReadOnly => write!(f, "Trie was opened read only"),
+            Error::Locked(ref dir) => write!(
+                f,
+                "Data directory {} is locked by another writer",
+                dir.display()
+            ),
+            Error::ReadersOpen(ref dir) => write!(
+                f,
+                "Data directory {} needs recovery, which has to wait until its read only stores are closed",
+                dir.display()
+            ),
+            Error::UnknownRoot(ref root) => write!(f, "Root {:x} not found in the store", root),
+            Error::NoJournal(ref root) => write!(f, "No journal for root {:x}", root),
         }
     }
 }
@@ -31,15 +51,7 @@ impl From<io::Error> for Error {
 }
 
 impl error::Error for Error {
-    fn description(&self) -> &str {
-        match *self {
-            Error::Io(ref err) => err.description(),
-            Error::MetaRootNotFound => "Meta root not found",
-            Error::NoLogFiles => "No log files",
-        }
-    }
-
-    fn cause(&self) -> Option<&error::Error> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
             Error::Io(ref err) => Some(err),
             _ => None,
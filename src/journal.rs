@@ -0,0 +1,273 @@
+//! Per-commit change journal.
+//!
+//! For every commit the journal keeps the keys that were written or removed,
+//! each with the leaf it had before the commit. The old leaf points at the
+//! old value, so a commit can be undone without diffing two roots.
+//!
+//! `Store` appends one record per commit to a file next to its log: the new
+//! root hash, then the position of the commit's meta and the entries,
+//! checksummed. A record torn by a crash is dropped the next time the store
+//! is opened for writing, and so are the records of commits that recovery
+//! drops from the log.
+use super::hasher::{hash, Digest};
+use super::node::{Node, LEAF_NODE_SIZE};
+use super::urkeldb::read_exact_at;
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Cursor, Read, Write};
+use std::path::Path;
+
+const JOURNAL_MAGIC: u32 = 0x6a726e6c;
+// magic (4) + payload size (4) + root hash (32) + payload hash (32)
+const RECORD_HEADER_SIZE: u64 = 72;
+
+const ENTRY_REMOVED: u8 = 1;
+const ENTRY_HAS_PREVIOUS: u8 = 2;
+// The previous leaf has its value in its record
+const ENTRY_INLINE_VALUE: u8 = 4;
+
+/// A key changed by a commit
+#[derive(Clone, Debug, PartialEq)]
+pub struct JournalEntry {
+    /// The hashed key
+    pub key: Digest,
+    /// The leaf the key had before the commit, or None if it wasn't in the
+    /// tree. It's a stored leaf without its value: read the old value with
+    /// `TrieStore::get`.
+    pub previous: Option<Node>,
+    /// Whether the commit removed the key (otherwise it set it)
+    pub removed: bool,
+}
+
+impl JournalEntry {
+    pub(crate) fn new(key: Digest, previous: Option<&Node>, removed: bool) -> Self {
+        let previous = previous.map(|leaf| {
+            let mut leaf = leaf.clone();
+            if let Node::Leaf { ref mut value, .. } = leaf {
+                *value = None;
+            }
+            leaf
+        });
+        JournalEntry {
+            key,
+            previous,
+            removed,
+        }
+    }
+
+    /// Key (32), flags (1), then the previous leaf (40) and its hash (32)
+    fn encode(&self, wtr: &mut Vec<u8>) -> io::Result<()> {
+        wtr.extend_from_slice(&self.key.0);
+        let mut flags = 0;
+        if self.removed {
+            flags |= ENTRY_REMOVED;
+        }
+        match self.previous {
+            Some(ref leaf) => {
+                if leaf.has_inline_value() {
+                    flags |= ENTRY_INLINE_VALUE;
+                }
+                wtr.write_u8(flags | ENTRY_HAS_PREVIOUS)?;
+                wtr.extend_from_slice(&leaf.encode_leaf()?);
+                wtr.extend_from_slice(&leaf.hash().0);
+            }
+            None => wtr.write_u8(flags)?,
+        }
+        Ok(())
+    }
+
+    fn decode(rdr: &mut Cursor<&[u8]>) -> io::Result<Self> {
+        let mut key = [0u8; 32];
+        rdr.read_exact(&mut key)?;
+        let flags = rdr.read_u8()?;
+        let previous = if flags & ENTRY_HAS_PREVIOUS != 0 {
+            let mut bits = [0u8; LEAF_NODE_SIZE + 32];
+            rdr.read_exact(&mut bits)?;
+            let mut leaf = Node::decode(&bits[..LEAF_NODE_SIZE], true)?;
+            leaf.update_data_value(Digest::from(&bits[LEAF_NODE_SIZE..]));
+            leaf.update_value_inline(flags & ENTRY_INLINE_VALUE != 0)?;
+            Some(leaf)
+        } else {
+            None
+        };
+        Ok(JournalEntry {
+            key: Digest(key),
+            previous,
+            removed: flags & ENTRY_REMOVED != 0,
+        })
+    }
+}
+
+/// Append only file of journal records
+pub(crate) struct JournalFile {
+    file: File,
+    len: u64,
+}
+
+impl JournalFile {
+    /// Open (or create, if writable) the journal. When writable, a torn
+    /// record at the end is dropped.
+    pub(crate) fn open(path: &Path, write: bool) -> io::Result<JournalFile> {
+        let file = if write {
+            OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(path)?
+        } else {
+            OpenOptions::new().read(true).open(path)?
+        };
+        let file_len = file.metadata()?.len();
+
+        let mut len = 0;
+        let mut reader = BufReader::new(&file);
+        while let Some(record) = read_record(&mut reader, None) {
+            len += record.size;
+        }
+        if write && len < file_len {
+            file.set_len(len)?;
+        }
+        Ok(JournalFile { file, len })
+    }
+
+    /// Append the record of the commit of 'root', whose meta is at
+    /// 'meta_pos' in the log
+    pub(crate) fn append(
+        &mut self,
+        root: Digest,
+        meta_pos: u32,
+        entries: &[JournalEntry],
+    ) -> io::Result<()> {
+        let mut payload = Vec::new();
+        payload.write_u32::<LittleEndian>(meta_pos)?;
+        for entry in entries {
+            entry.encode(&mut payload)?;
+        }
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE as usize + payload.len());
+        record.write_u32::<LittleEndian>(JOURNAL_MAGIC)?;
+        record.write_u32::<LittleEndian>(payload.len() as u32)?;
+        record.extend_from_slice(&root.0);
+        record.extend_from_slice(&hash(&payload).0);
+        record.extend_from_slice(&payload);
+
+        self.file.write_all(&record)?;
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Drop the records of commits past 'end' in the log, once recovery has
+    /// truncated it there
+    pub(crate) fn truncate(&mut self, end: u32) -> io::Result<()> {
+        let mut len = 0;
+        let mut reader = FileReader {
+            file: &self.file,
+            pos: 0,
+        };
+        while len < self.len {
+            match read_record(&mut reader, None) {
+                Some(record) if record.meta_pos < end => len += record.size,
+                _ => break,
+            }
+        }
+        if len < self.len {
+            self.file.set_len(len)?;
+            self.file.sync_all()?;
+            self.len = len;
+        }
+        Ok(())
+    }
+
+    /// The entries of the last record for 'root'
+    pub(crate) fn find(&self, root: Digest) -> io::Result<Option<Vec<JournalEntry>>> {
+        let mut found = None;
+        let mut pos = 0;
+        while pos < self.len {
+            let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+            read_exact_at(&self.file, &mut header, pos)?;
+            let size = LittleEndian::read_u32(&header[4..8]);
+            if Digest::from(&header[8..40]) == root {
+                found = Some(pos);
+            }
+            pos += RECORD_HEADER_SIZE + u64::from(size);
+        }
+
+        match found {
+            Some(pos) => {
+                let mut reader = FileReader {
+                    file: &self.file,
+                    pos,
+                };
+                match read_record(&mut reader, Some(root)) {
+                    Some(record) => Ok(Some(record.entries)),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Corrupt journal record",
+                    )),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// A record as read back
+struct Record {
+    meta_pos: u32,
+    entries: Vec<JournalEntry>,
+    // Bytes in the file, header included
+    size: u64,
+}
+
+/// Read one record. Returns None at the end of the journal or if the record
+/// is incomplete or corrupt. With 'root', the record must be for that root.
+fn read_record<R: Read>(reader: &mut R, root: Option<Digest>) -> Option<Record> {
+    let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+    reader.read_exact(&mut header).ok()?;
+    let mut rdr = Cursor::new(&header[..]);
+    if rdr.read_u32::<LittleEndian>().ok()? != JOURNAL_MAGIC {
+        return None;
+    }
+    let size = rdr.read_u32::<LittleEndian>().ok()?;
+    let record_root = Digest::from(&header[8..40]);
+    let checksum = Digest::from(&header[40..72]);
+    if root.is_some_and(|r| r != record_root) {
+        return None;
+    }
+
+    let mut payload = vec![0u8; size as usize];
+    reader.read_exact(&mut payload).ok()?;
+    if hash(&payload) != checksum {
+        return None;
+    }
+
+    let mut rdr = Cursor::new(&payload[..]);
+    let meta_pos = rdr.read_u32::<LittleEndian>().ok()?;
+    let mut entries = Vec::new();
+    while (rdr.position() as usize) < payload.len() {
+        entries.push(JournalEntry::decode(&mut rdr).ok()?);
+    }
+    Some(Record {
+        meta_pos,
+        entries,
+        size: RECORD_HEADER_SIZE + u64::from(size),
+    })
+}
+
+/// Sequential reads from a position without moving a shared cursor
+struct FileReader<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl<'a> Read for FileReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        read_exact_at(self.file, buf, self.pos)?;
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+}
@@ -0,0 +1,631 @@
+//! A `TrieStore` on top of a generic key-value database.
+//!
+//! `KvStore` keeps each encoded node under its hash, so any ordered
+//! key-value store that can apply a batch of writes atomically can hold the
+//! trie. Leaf values are stored in the same record as their leaf, so a
+//! single lookup resolves both. Everything saved during a commit is written
+//! in one `KvBackend::batch` along with the new root.
+//!
+//! `FileBackend` is a small embedded engine on the local filesystem.
+//! `RwLock<BTreeMap<Vec<u8>, Vec<u8>>>` also implements `KvBackend` for
+//! tests.
+use super::errors::Result;
+use super::hasher::{hash, Digest};
+use super::journal::JournalEntry;
+use super::node::{Node, LEAF_NODE_SIZE};
+use super::urkeldb::{lock_dir, maybe_create_dir, read_exact_at};
+use super::{CommitInfo, RootInfo, TrieStore};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Cursor, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+/// Nodes are addressed by hash, so they all share one storage location.
+/// Index 0 is reserved for unsaved nodes.
+const KV_INDEX: u16 = 1;
+
+const NODE_PREFIX: u8 = b'n';
+const HISTORY_PREFIX: u8 = b'r';
+const ROOT_KEY: &[u8] = b"R";
+
+const ROOT_EMPTY: u8 = 0;
+const ROOT_INTERNAL: u8 = 1;
+const ROOT_LEAF: u8 = 2;
+
+/// A single write in a batch
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KvOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+/// The operations `KvStore` needs from a key-value database. To keep trie
+/// writes atomic with your own indexes, pass your writes to
+/// `UrkelTrie::commit_with_ops` and they go into the same `batch`.
+///
+/// Like `TrieStore`, all methods take `&self` and backends keep their own
+/// locks. A batch should only block readers while it's made visible, not
+/// while it's written out.
+pub trait KvBackend {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>>;
+
+    fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()>;
+
+    fn delete(&self, key: &[u8]) -> io::Result<()>;
+
+    /// Apply all the ops or none of them
+    fn batch(&self, ops: Vec<KvOp>) -> io::Result<()>;
+}
+
+impl KvBackend for RwLock<BTreeMap<Vec<u8>, Vec<u8>>> {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.read().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.write().unwrap().insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> io::Result<()> {
+        self.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn batch(&self, ops: Vec<KvOp>) -> io::Result<()> {
+        let mut map = self.write().unwrap();
+        for op in ops {
+            match op {
+                KvOp::Put(k, v) => map.insert(k, v),
+                KvOp::Delete(k) => map.remove(&k),
+            };
+        }
+        Ok(())
+    }
+}
+
+/// `TrieStore` storing encoded nodes by hash in a `KvBackend`
+pub struct KvStore<B: KvBackend> {
+    backend: B,
+    // Writes staged by 'save' until the next commit
+    pending: Mutex<Vec<KvOp>>,
+}
+
+impl<B: KvBackend> KvStore<B> {
+    pub fn new(backend: B) -> Self {
+        KvStore {
+            backend,
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn into_backend(self) -> B {
+        self.backend
+    }
+
+    /// Commit 'root' and apply 'ops' in the same batch, so they land
+    /// together or not at all. Keys starting with `n` or `r`, and the key
+    /// `R`, hold the trie and can't be written here.
+    pub fn commit_with_ops(
+        &self,
+        root: Box<Node>,
+        meta: &[u8],
+        ops: Vec<KvOp>,
+    ) -> io::Result<CommitInfo> {
+        if let Some(op) = ops.iter().find(|op| is_trie_key(op.key())) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Key {:?} is reserved for the trie", op.key()),
+            ));
+        }
+
+        let bits = encode_root(&root, meta);
+        let mut batch: Vec<KvOp> = self.pending.lock().unwrap().drain(..).collect();
+        batch.extend(ops);
+        batch.push(KvOp::Put(history_key(root.hash()), bits.clone()));
+        batch.push(KvOp::Put(ROOT_KEY.to_vec(), bits));
+        // Keys and values handed to the backend
+        let bytes = batch
+            .iter()
+            .map(|op| match op {
+                KvOp::Put(key, value) => key.len() + value.len(),
+                KvOp::Delete(key) => key.len(),
+            })
+            .sum::<usize>() as u64;
+        self.backend.batch(batch)?;
+        Ok(CommitInfo {
+            root: root.hash(),
+            bytes,
+            ..CommitInfo::default()
+        })
+    }
+
+    fn read_record(&self, hash: Digest) -> io::Result<Vec<u8>> {
+        self.backend.get(&node_key(hash))?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Node {:x} not found", hash),
+            )
+        })
+    }
+}
+
+impl<B: KvBackend> TrieStore for KvStore<B> {
+    fn save(&self, mut node: Node) -> io::Result<Box<Node>> {
+        let mut record = node.encode()?;
+        if let Node::Leaf { ref value, .. } = node {
+            match value {
+                Some(v) => record.extend_from_slice(v),
+                None => return Err(io::Error::other("Leaf has no value!")),
+            }
+            node.update_value_storage_location(KV_INDEX, 0);
+        }
+        node.update_storage_location(KV_INDEX, 0);
+
+        let hashed = node.into_hash_node();
+        self.pending
+            .lock()
+            .unwrap()
+            .push(KvOp::Put(node_key(hashed.hash()), record));
+        Ok(hashed.into_boxed())
+    }
+
+    fn get(&self, leaf: &Node) -> io::Result<Vec<u8>> {
+        let mut record = self.read_record(leaf.hash())?;
+        if record.len() < LEAF_NODE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Leaf record too short",
+            ));
+        }
+        Ok(record.split_off(LEAF_NODE_SIZE))
+    }
+
+    fn get_root(&self) -> io::Result<Box<Node>> {
+        match self.backend.get(ROOT_KEY)? {
+            Some(bits) => decode_root(&bits),
+            None => Ok(Node::empty().into_boxed()),
+        }
+    }
+
+    fn resolve(&self, node: Node) -> io::Result<Box<Node>> {
+        let hash = node.get_data_value();
+        let mut record = self.read_record(hash)?;
+        let is_leaf = node.is_leaf();
+
+        let mut n = if is_leaf {
+            if record.len() < LEAF_NODE_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Leaf record too short",
+                ));
+            }
+            let value = record.split_off(LEAF_NODE_SIZE);
+            let mut leaf = Node::decode(&record, true)?;
+            if let Node::Leaf {
+                value: ref mut v, ..
+            } = leaf
+            {
+                *v = Some(value);
+            }
+            leaf
+        } else {
+            Node::decode(&record, false)?
+        };
+        n.update_storage_location(KV_INDEX, 0);
+        n.update_data_value(hash);
+        Ok(n.into_boxed())
+    }
+
+    fn commit(&self, root: Box<Node>) -> io::Result<Box<Node>> {
+        self.commit_with(root.clone(), &[], &[])?;
+        Ok(root)
+    }
+
+    fn commit_with(
+        &self,
+        root: Box<Node>,
+        meta: &[u8],
+        _journal: &[JournalEntry],
+    ) -> io::Result<CommitInfo> {
+        self.commit_with_ops(root, meta, Vec::new())
+    }
+
+    fn find_root(&self, root_hash: Digest) -> io::Result<Option<Box<Node>>> {
+        match self.backend.get(&history_key(root_hash))? {
+            Some(bits) => decode_root(&bits).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// History is keyed by root hash, so only the last root can be walked.
+    /// Earlier ones can still be looked up with `root_info`.
+    fn walk_roots(&self, f: &mut dyn FnMut(RootInfo) -> bool) -> io::Result<()> {
+        if let Some(bits) = self.backend.get(ROOT_KEY)? {
+            let root = decode_root(&bits)?;
+            if !root.is_empty() {
+                f(RootInfo {
+                    hash: root.hash(),
+                    meta: bits[33..].to_vec(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn root_info(&self, root_hash: Digest) -> io::Result<Option<RootInfo>> {
+        match self.backend.get(&history_key(root_hash))? {
+            Some(bits) => Ok(Some(RootInfo {
+                hash: decode_root(&bits)?.hash(),
+                meta: bits[33..].to_vec(),
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+impl KvOp {
+    fn key(&self) -> &[u8] {
+        match self {
+            KvOp::Put(key, _) | KvOp::Delete(key) => key,
+        }
+    }
+}
+
+fn is_trie_key(key: &[u8]) -> bool {
+    match key.first() {
+        Some(&p) => key == ROOT_KEY || p == NODE_PREFIX || p == HISTORY_PREFIX,
+        None => false,
+    }
+}
+
+fn node_key(hash: Digest) -> Vec<u8> {
+    let mut key = Vec::with_capacity(33);
+    key.push(NODE_PREFIX);
+    key.extend_from_slice(&hash.0);
+    key
+}
+
+fn history_key(hash: Digest) -> Vec<u8> {
+    let mut key = Vec::with_capacity(33);
+    key.push(HISTORY_PREFIX);
+    key.extend_from_slice(&hash.0);
+    key
+}
+
+/// Root record: kind (u8), the root hash, then the commit's metadata
+fn encode_root(root: &Node, meta: &[u8]) -> Vec<u8> {
+    let kind = match root {
+        Node::Empty {} => ROOT_EMPTY,
+        n if n.is_leaf() => ROOT_LEAF,
+        _ => ROOT_INTERNAL,
+    };
+    let mut bits = Vec::with_capacity(33 + meta.len());
+    bits.push(kind);
+    bits.extend_from_slice(&root.hash().0);
+    bits.extend_from_slice(meta);
+    bits
+}
+
+fn decode_root(bits: &[u8]) -> io::Result<Box<Node>> {
+    if bits.len() < 33 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Corrupt root record",
+        ));
+    }
+    let hash = Digest::from(&bits[1..33]);
+    let node = match bits[0] {
+        ROOT_EMPTY => Node::empty(),
+        ROOT_LEAF => Node::new_hash_node(KV_INDEX, 0, hash, true),
+        _ => Node::new_hash_node(KV_INDEX, 0, hash, false),
+    };
+    Ok(node.into_boxed())
+}
+
+const KV_LOG_FILENAME: &str = "kv.log";
+const KV_COMPACT_FILENAME: &str = "kv.log.compact";
+// Live entries are copied into batches of about this size
+const COMPACT_BATCH_SIZE: usize = 4 * 1024 * 1024;
+const BATCH_MAGIC: u32 = 0x6b766274;
+// magic (4) + payload size (4) + payload hash (32)
+const BATCH_HEADER_SIZE: u64 = 40;
+
+const OP_DELETE: u8 = 0;
+const OP_PUT: u8 = 1;
+
+/// A simple embedded key-value engine. Every batch is appended to a single
+/// log file as one checksummed record, and an ordered in-memory index maps
+/// keys to where their values are in the log. On open the log is replayed,
+/// and a torn batch at the end (from a crash mid-write) is dropped, so
+/// batches are atomic.
+///
+/// Overwritten and deleted entries stay in the log until `compact` is
+/// called. `KvStore` never deletes nodes, so only the root record and the
+/// caller's own keys leave garbage behind.
+pub struct FileBackend {
+    dir: PathBuf,
+    // Appends batches. Held through the write and the sync, so batches go
+    // out one at a time without holding up readers.
+    writer: Mutex<LogWriter>,
+    // Only locked for writing to make a durable batch visible
+    log: RwLock<LogIndex>,
+    // Holds the exclusive lock on the directory. Released on drop.
+    _lock: File,
+}
+
+struct LogWriter {
+    file: File,
+    len: u64,
+}
+
+struct LogIndex {
+    file: File,
+    // Where the value of each live key is in the log
+    index: BTreeMap<Vec<u8>, (u64, u32)>,
+}
+
+// Where each key of a batch landed in the log, None if it was deleted
+type Updates = Vec<(Vec<u8>, Option<(u64, u32)>)>;
+
+impl FileBackend {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<FileBackend> {
+        let dir = dir.as_ref();
+        maybe_create_dir(dir);
+        let lock = lock_dir(dir)?;
+
+        // Left by a compaction that didn't finish. The log is still whole.
+        let compacted = dir.join(KV_COMPACT_FILENAME);
+        if compacted.exists() {
+            fs::remove_file(compacted)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(dir.join(KV_LOG_FILENAME))?;
+        let file_len = file.metadata()?.len();
+
+        // Replay the batches
+        let mut index = BTreeMap::new();
+        let mut len = 0;
+        let mut reader = BufReader::new(&file);
+        while let Some(size) = replay_batch(&mut reader, len, &mut index) {
+            len += size;
+        }
+
+        // Drop a torn batch at the end
+        if len < file_len {
+            file.set_len(len)?;
+        }
+
+        Ok(FileBackend {
+            dir: dir.to_path_buf(),
+            log: RwLock::new(LogIndex {
+                file: file.try_clone()?,
+                index,
+            }),
+            writer: Mutex::new(LogWriter { file, len }),
+            _lock: lock,
+        })
+    }
+
+    /// Size of the log in bytes
+    pub fn log_size(&self) -> u64 {
+        self.writer.lock().unwrap().len
+    }
+
+    /// Rewrite the log with only the live entries, dropping overwritten and
+    /// deleted ones. The new log replaces the old one with a rename, so a
+    /// crash leaves one or the other. Reads go on meanwhile; batches wait.
+    pub fn compact(&self) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let path = self.dir.join(KV_COMPACT_FILENAME);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+
+        let mut index = BTreeMap::new();
+        let mut len = 0;
+        {
+            let log = self.log.read().unwrap();
+            let mut ops = Vec::new();
+            let mut size = 0;
+            for (key, &(pos, vlen)) in &log.index {
+                let mut value = vec![0u8; vlen as usize];
+                read_exact_at(&log.file, &mut value, pos)?;
+                size += key.len() + value.len();
+                ops.push(KvOp::Put(key.clone(), value));
+                if size >= COMPACT_BATCH_SIZE {
+                    let (written, updates) = write_batch(&mut file, len, mem::take(&mut ops))?;
+                    apply_updates(&mut index, updates);
+                    len += written;
+                    size = 0;
+                }
+            }
+            if !ops.is_empty() {
+                let (written, updates) = write_batch(&mut file, len, ops)?;
+                apply_updates(&mut index, updates);
+                len += written;
+            }
+        }
+        file.sync_all()?;
+        drop(file);
+
+        let path_log = self.dir.join(KV_LOG_FILENAME);
+        fs::rename(&path, &path_log)?;
+        sync_dir(&self.dir)?;
+
+        let file = OpenOptions::new().read(true).append(true).open(path_log)?;
+        *self.log.write().unwrap() = LogIndex {
+            file: file.try_clone()?,
+            index,
+        };
+        *writer = LogWriter { file, len };
+        Ok(())
+    }
+}
+
+impl KvBackend for FileBackend {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let log = self.log.read().unwrap();
+        match log.index.get(key) {
+            Some(&(pos, size)) => {
+                let mut value = vec![0u8; size as usize];
+                read_exact_at(&log.file, &mut value, pos)?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.batch(vec![KvOp::Put(key.to_vec(), value.to_vec())])
+    }
+
+    fn delete(&self, key: &[u8]) -> io::Result<()> {
+        self.batch(vec![KvOp::Delete(key.to_vec())])
+    }
+
+    fn batch(&self, ops: Vec<KvOp>) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let offset = writer.len;
+        let (written, updates) = match write_batch(&mut writer.file, offset, ops) {
+            Ok(batch) => batch,
+            Err(e) => {
+                // Don't leave part of the batch for the next one to follow
+                writer.file.set_len(offset)?;
+                return Err(e);
+            }
+        };
+        writer.len += written;
+
+        // Only visible once durable
+        apply_updates(&mut self.log.write().unwrap().index, updates);
+        Ok(())
+    }
+}
+
+/// Append 'ops' as one batch to 'file', which ends at 'offset', and sync
+/// it. Returns the size of the batch and where its keys landed.
+fn write_batch(file: &mut File, offset: u64, ops: Vec<KvOp>) -> io::Result<(u64, Updates)> {
+    // Where the payload will start in the file
+    let start = offset + BATCH_HEADER_SIZE;
+
+    // Encode the ops, noting where each value lands
+    let mut payload = Vec::new();
+    let mut updates = Vec::with_capacity(ops.len());
+    for op in ops {
+        match op {
+            KvOp::Put(key, value) => {
+                let vlen = size_field::<u32>(value.len(), "Value")?;
+                payload.write_u8(OP_PUT)?;
+                payload.write_u16::<LittleEndian>(size_field(key.len(), "Key")?)?;
+                payload.extend_from_slice(&key);
+                payload.write_u32::<LittleEndian>(vlen)?;
+                let pos = start + payload.len() as u64;
+                payload.extend_from_slice(&value);
+                updates.push((key, Some((pos, vlen))));
+            }
+            KvOp::Delete(key) => {
+                payload.write_u8(OP_DELETE)?;
+                payload.write_u16::<LittleEndian>(size_field(key.len(), "Key")?)?;
+                payload.extend_from_slice(&key);
+                updates.push((key, None));
+            }
+        }
+    }
+
+    let mut record = Vec::with_capacity(BATCH_HEADER_SIZE as usize + payload.len());
+    record.write_u32::<LittleEndian>(BATCH_MAGIC)?;
+    record.write_u32::<LittleEndian>(size_field(payload.len(), "Batch")?)?;
+    record.extend_from_slice(&hash(&payload).0);
+    record.extend_from_slice(&payload);
+
+    file.write_all(&record)?;
+    file.sync_data()?;
+    Ok((record.len() as u64, updates))
+}
+
+fn apply_updates(index: &mut BTreeMap<Vec<u8>, (u64, u32)>, updates: Updates) {
+    for (key, loc) in updates {
+        match loc {
+            Some(loc) => index.insert(key, loc),
+            None => index.remove(&key),
+        };
+    }
+}
+
+/// Make a rename in 'dir' durable
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(windows)]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Convert a length to the width of its field in the log, or fail if it
+/// doesn't fit
+fn size_field<T: TryFrom<usize>>(len: usize, what: &str) -> io::Result<T> {
+    T::try_from(len).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} too large: {} bytes", what, len),
+        )
+    })
+}
+
+/// Read one batch at 'offset' into the index. Returns the size of the batch,
+/// or None at the end of the log or if the batch is incomplete or corrupt.
+fn replay_batch<R: Read>(
+    reader: &mut R,
+    offset: u64,
+    index: &mut BTreeMap<Vec<u8>, (u64, u32)>,
+) -> Option<u64> {
+    let mut header = [0u8; BATCH_HEADER_SIZE as usize];
+    reader.read_exact(&mut header).ok()?;
+    let mut rdr = Cursor::new(&header[..]);
+    if rdr.read_u32::<LittleEndian>().ok()? != BATCH_MAGIC {
+        return None;
+    }
+    let size = rdr.read_u32::<LittleEndian>().ok()?;
+    let checksum = Digest::from(&header[8..]);
+
+    let mut payload = vec![0u8; size as usize];
+    reader.read_exact(&mut payload).ok()?;
+    if hash(&payload) != checksum {
+        return None;
+    }
+
+    let start = offset + BATCH_HEADER_SIZE;
+    let mut rdr = Cursor::new(&payload[..]);
+    while (rdr.position() as usize) < payload.len() {
+        let op = rdr.read_u8().ok()?;
+        let klen = rdr.read_u16::<LittleEndian>().ok()?;
+        let mut key = vec![0u8; klen as usize];
+        rdr.read_exact(&mut key).ok()?;
+        if op == OP_PUT {
+            let vlen = rdr.read_u32::<LittleEndian>().ok()?;
+            let pos = start + rdr.position();
+            rdr.set_position(rdr.position() + u64::from(vlen));
+            index.insert(key, (pos, vlen));
+        } else {
+            index.remove(&key);
+        }
+    }
+    Some(BATCH_HEADER_SIZE + u64::from(size))
+}
@@ -2,47 +2,172 @@
 
 extern crate blake2_rfc;
 extern crate byteorder;
+#[cfg(feature = "encryption")]
+extern crate chacha20poly1305;
+extern crate fs2;
+extern crate lru;
 //#[macro_use]
 extern crate log;
+#[cfg(feature = "lz4")]
+extern crate lz4_flex;
+#[cfg(feature = "mmap")]
+extern crate memmap2;
+#[cfg(feature = "parallel")]
+extern crate rayon;
 
 //mod db;
+mod cache;
+mod dedup;
+pub mod diff;
+#[cfg(feature = "encryption")]
+pub mod encryption;
 mod errors;
 pub mod hasher;
-mod node;
+pub mod journal;
+pub mod kvstore;
+pub mod memorystore;
+pub mod node;
 pub mod proof;
+pub mod shared;
 pub mod trie;
 mod urkeldb;
 
+pub use crate::cache::CacheStats;
+#[cfg(feature = "encryption")]
+pub use crate::encryption::Encryption;
+pub use crate::errors::{Error, Result};
 use crate::hasher::Digest;
+use crate::journal::JournalEntry;
 use crate::node::Node;
+pub use crate::urkeldb::{Options, SyncPolicy};
 use std::io;
+use std::time::Duration;
 
 /// Common function used in several places in the tree and proof to determine which
 /// direction to go in the tree.
 pub fn has_bit(key: &Digest, index: usize) -> bool {
     let oct = index >> 3;
     let bit = index & 7;
-    match (key.0[oct] >> (7 - bit)) & 1 {
-        1 => true,
-        _ => false,
-    }
+    (key.0[oct] >> (7 - bit)) & 1 == 1
+}
+
+/// A committed root and the metadata it was committed with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootInfo {
+    pub hash: Digest,
+    /// The blob passed to `UrkelTrie::commit_with`, empty for `commit`
+    pub meta: Vec<u8>,
+}
+
+/// What a commit did. Stores fill in what they can; the rest is zero.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommitInfo {
+    /// Hash of the new root
+    pub root: Digest,
+    /// Nodes written (leaves and internal nodes)
+    pub nodes: usize,
+    /// Leaf values written
+    pub values: usize,
+    /// Leaves saved without writing their value, because the store already
+    /// had a copy (see `Options::dedup_values`). Not counted in `values`.
+    pub deduplicated: usize,
+    /// Bytes appended to the store, including the meta
+    pub bytes: u64,
+    /// File index of the meta written for the root
+    pub meta_index: u16,
+    /// Position of the meta in its file
+    pub meta_pos: u32,
+    /// Time spent writing and syncing
+    pub elapsed: Duration,
 }
 
+/// Storage backend for the trie. Implement this to keep the trie's nodes in
+/// your own database. See the `node` module for how a store is expected to
+/// treat nodes. `UrkelTrie` only ever hands a store nodes it created, and
+/// only asks it to resolve hash nodes the store returned.
+///
+/// All methods take `&self` so readers can resolve nodes while a writer is
+/// saving and committing (see `shared::SharedTrie`). Stores keep their
+/// write state behind their own locks. Reads are only ever for nodes that
+/// were committed, so they don't need to see anything staged by `save`.
 pub trait TrieStore {
-    /// Write a node to storage. This consumes the incoming node and returns
-    /// a boxed hash node.
-    fn save(&mut self, node: Node) -> Box<Node>;
+    /// Write a node to storage. This consumes the incoming node (a leaf or
+    /// internal node) and returns a boxed hash node pointing to where it was
+    /// written. Children of an internal node have already been saved.
+    fn save(&self, node: Node) -> io::Result<Box<Node>>;
 
-    /// Get the value for given leaf node
-    fn get(&self, vindex: u16, vpos: u32, vsize: u16) -> Option<Vec<u8>>;
+    /// Get the value for the given leaf node
+    fn get(&self, leaf: &Node) -> io::Result<Vec<u8>>;
 
-    /// Get the root node from storage
+    /// Get the last committed root from storage. An error (or an empty node)
+    /// means nothing has been committed yet.
     fn get_root(&self) -> io::Result<Box<Node>>;
 
     /// Resolve a hash node from storage. Consumes the current node and returns
     /// a boxed version of the underlying node
-    fn resolve(&self, node: Node) -> Box<Node>;
+    fn resolve(&self, node: Node) -> io::Result<Box<Node>>;
+
+    /// Commit a new root to storage. Everything saved since the last commit
+    /// must be durable once this returns.
+    fn commit(&self, root: Box<Node>) -> io::Result<Box<Node>>;
+
+    /// Find a previously committed root by its hash. The default only knows
+    /// about the last committed root; stores that keep their history should
+    /// look further back.
+    fn find_root(&self, root_hash: Digest) -> io::Result<Option<Box<Node>>> {
+        let root = self.get_root()?;
+        Ok(Some(root).filter(|r| r.hash() == root_hash))
+    }
 
-    /// Commit a new root to storage
-    fn commit(&mut self, root: Box<Node>) -> io::Result<(Box<Node>)>;
+    /// Commit a new root along with an opaque blob of metadata for it and
+    /// the keys the commit changed (see the `journal` module). Stores that
+    /// keep neither just commit. Returns what the store knows about the
+    /// commit: at least the root hash.
+    fn commit_with(
+        &self,
+        root: Box<Node>,
+        _meta: &[u8],
+        _journal: &[JournalEntry],
+    ) -> io::Result<CommitInfo> {
+        let hash = root.hash();
+        self.commit(root)?;
+        Ok(CommitInfo {
+            root: hash,
+            ..CommitInfo::default()
+        })
+    }
+
+    /// Call 'f' for each committed root, newest first, until it returns
+    /// false. The default only knows about the last committed root, without
+    /// its metadata.
+    fn walk_roots(&self, f: &mut dyn FnMut(RootInfo) -> bool) -> io::Result<()> {
+        let root = self.get_root()?;
+        if !root.is_empty() {
+            f(RootInfo {
+                hash: root.hash(),
+                meta: Vec::new(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The root 'root_hash' and its metadata, if the store still has it. If
+    /// it was committed more than once, the latest. The default walks the
+    /// roots; stores that index their history by hash should look it up.
+    fn root_info(&self, root_hash: Digest) -> io::Result<Option<RootInfo>> {
+        let mut found = None;
+        self.walk_roots(&mut |info| {
+            if info.hash == root_hash {
+                found = Some(info);
+            }
+            found.is_none()
+        })?;
+        Ok(found)
+    }
+
+    /// The journal recorded when 'root_hash' was committed, if the store
+    /// kept one
+    fn get_journal(&self, _root_hash: Digest) -> io::Result<Option<Vec<JournalEntry>>> {
+        Ok(None)
+    }
 }
@@ -0,0 +1,158 @@
+use super::hasher::Digest;
+use super::journal::JournalEntry;
+use super::node::{Node, INTERNAL_NODE_SIZE, LEAF_NODE_SIZE};
+use super::{CommitInfo, RootInfo, TrieStore};
+use std::convert::TryFrom;
+use std::io;
+use std::sync::RwLock;
+
+/// All nodes live in a single in-memory 'file'. 0 is reserved for
+/// nodes that haven't been saved.
+const MEMORY_FILE_INDEX: u16 = 1;
+
+/// A `TrieStore` that keeps everything in memory. Nodes and values are
+/// encoded into an append only buffer the same way `Store` writes them to
+/// disk, so it behaves like the on-disk store minus persistence. Useful for
+/// tests and throwaway tries.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: RwLock<Vec<u8>>,
+    // Committed roots as hash nodes with their metadata, oldest first
+    roots: RwLock<Vec<(Node, Vec<u8>)>>,
+    // Journals by root hash, oldest first
+    journals: RwLock<Vec<(Digest, Vec<JournalEntry>)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    fn append(&self, bits: &[u8]) -> u32 {
+        let mut data = self.data.write().unwrap();
+        let pos = data.len() as u32;
+        data.extend_from_slice(bits);
+        pos
+    }
+
+    fn read(&self, index: u16, pos: u32, size: usize) -> io::Result<Vec<u8>> {
+        let data = self.data.read().unwrap();
+        let start = pos as usize;
+        if index != MEMORY_FILE_INDEX || start + size > data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Read past the end of the memory store",
+            ));
+        }
+        Ok(data[start..start + size].to_vec())
+    }
+
+    fn read_node(&self, index: u16, pos: u32, is_leaf: bool) -> io::Result<Node> {
+        let size = if is_leaf {
+            LEAF_NODE_SIZE
+        } else {
+            INTERNAL_NODE_SIZE
+        };
+        self.read(index, pos, size)
+            .and_then(|bits| Node::decode(&bits, is_leaf))
+    }
+}
+
+impl TrieStore for MemoryStore {
+    fn save(&self, mut node: Node) -> io::Result<Box<Node>> {
+        if let Node::Leaf { ref value, .. } = node {
+            // Write value first. Its size has to fit the leaf's field.
+            let (val_pos, size) = match value {
+                Some(v) => {
+                    let size = u16::try_from(v.len())
+                        .map_err(|_| io::Error::other("Value too large to store"))?;
+                    (self.append(v), size)
+                }
+                None => return Err(io::Error::other("Leaf has no value!")),
+            };
+            node.update_value_storage_location(MEMORY_FILE_INDEX, val_pos);
+            node.update_value_stored_size(size, false);
+        }
+        let pos = node.encode().map(|bits| self.append(&bits))?;
+        node.update_storage_location(MEMORY_FILE_INDEX, pos);
+        Ok(node.into_hash_node().into_boxed())
+    }
+
+    fn get(&self, leaf: &Node) -> io::Result<Vec<u8>> {
+        let (vindex, vpos, vsize) = leaf.get_value_storage_location();
+        self.read(vindex, vpos, vsize as usize)
+    }
+
+    fn get_root(&self) -> io::Result<Box<Node>> {
+        Ok(self
+            .roots
+            .read()
+            .unwrap()
+            .last()
+            .map(|(root, _)| root.clone())
+            .unwrap_or(Node::Empty {})
+            .into_boxed())
+    }
+
+    fn resolve(&self, node: Node) -> io::Result<Box<Node>> {
+        let (index, pos) = node.get_storage_location();
+        self.read_node(index, pos, node.is_leaf()).map(|mut n| {
+            n.update_storage_location(index, pos);
+            n.update_data_value(node.get_data_value());
+            n.into_boxed()
+        })
+    }
+
+    fn commit(&self, root: Box<Node>) -> io::Result<Box<Node>> {
+        self.roots
+            .write()
+            .unwrap()
+            .push(((*root).clone(), Vec::new()));
+        Ok(root)
+    }
+
+    fn find_root(&self, root_hash: Digest) -> io::Result<Option<Box<Node>>> {
+        let roots = self.roots.read().unwrap();
+        let found = roots.iter().rev().find(|(r, _)| r.hash() == root_hash);
+        Ok(found.map(|(r, _)| r.clone().into_boxed()))
+    }
+
+    fn commit_with(
+        &self,
+        root: Box<Node>,
+        meta: &[u8],
+        journal: &[JournalEntry],
+    ) -> io::Result<CommitInfo> {
+        self.journals
+            .write()
+            .unwrap()
+            .push((root.hash(), journal.to_vec()));
+        self.roots
+            .write()
+            .unwrap()
+            .push(((*root).clone(), meta.to_vec()));
+        Ok(CommitInfo {
+            root: root.hash(),
+            ..CommitInfo::default()
+        })
+    }
+
+    fn walk_roots(&self, f: &mut dyn FnMut(RootInfo) -> bool) -> io::Result<()> {
+        for (root, meta) in self.roots.read().unwrap().iter().rev() {
+            let info = RootInfo {
+                hash: root.hash(),
+                meta: meta.clone(),
+            };
+            if !f(info) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_journal(&self, root_hash: Digest) -> io::Result<Option<Vec<JournalEntry>>> {
+        let journals = self.journals.read().unwrap();
+        let found = journals.iter().rev().find(|(root, _)| *root == root_hash);
+        Ok(found.map(|(_, journal)| journal.clone()))
+    }
+}
@@ -1,25 +1,63 @@
+//! Trie nodes.
+//!
+//! Variants can be matched on, but outside this crate nodes can only be built
+//! with the constructors on `Node`. A `TrieStore` sees nodes at two points:
+//!
+//! - `save` gets a leaf or internal node. The store writes it (`encode` gives
+//!   a compact encoding whose internal nodes refer to their children by
+//!   storage location and hash), records the location with
+//!   `update_storage_location` (and `update_value_storage_location` for the
+//!   value of a leaf), and returns `into_hash_node()`.
+//! - `resolve` gets one of those hash nodes back (`get_storage_location`,
+//!   `is_leaf`) and returns the leaf or internal node it points to, e.g. via
+//!   `decode` followed by `update_storage_location` and
+//!   `update_data_value(hash_node.get_data_value())`. The resolved node must
+//!   keep its storage location, or the trie will treat it as unsaved. A
+//!   store that read the leaf's value along with it can attach it with
+//!   `update_value`, and the trie won't ask for it with `get`.
+//!
+//! A store can keep a leaf's value in the leaf's own record. It marks the
+//! leaf with `update_value_inline` before `into_hash_node`, and the flag is
+//! kept in the pointers to the leaf (`has_inline_value`), so the store knows
+//! the size of the record before reading it.
+//!
+//! A storage location with index 0 means "not saved", so stores must hand out
+//! non-zero indexes.
 use super::hasher::{hash_internal, hash_leaf_value, Digest};
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io;
-use std::io::{Cursor, Error, ErrorKind};
+use std::io::{Cursor, Error};
 
 pub const LEAF_NODE_SIZE: usize = 40;
 pub const INTERNAL_NODE_SIZE: usize = 76;
+// Set in the encoded value index of a leaf whose value is stored compressed
+const COMPRESSED_VALUE_FLAG: u16 = 0x8000;
+// Set in the encoded file index of a child that's a leaf with its value
+// inline
+const INLINE_VALUE_FLAG: u16 = 0x8000;
+/// Largest file index a node can refer to. Indexes are stored doubled, with
+/// the top bit taken by a flag.
+pub const MAX_FILE_INDEX: u16 = 0x3fff;
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum Node {
     /// Sentinal node
+    #[non_exhaustive]
     Empty {},
     /// Compact representation of a leaf/internal node used in storage.
     /// The is_leaf flag is set during encoding/decoding (not persisted)
+    #[non_exhaustive]
     Hash {
         index: u16,
         pos: u32,
         data: Digest,
         is_leaf: u8,
+        /// Points to a leaf whose value is in the leaf's record
+        inline: bool,
     },
     /// Holds actual key/value along with positional information for both
-    /// the leaf node and the leaf value as they are stored in different places  
+    /// the leaf node and the leaf value as they are stored in different places
+    #[non_exhaustive]
     Leaf {
         index: u16,
         pos: u32,
@@ -29,8 +67,15 @@ pub enum Node {
         vindex: u16,
         vpos: u32,
         vsize: u16,
+        /// The value is stored compressed. 'vsize' is the stored size.
+        compressed: bool,
+        /// The value is stored in the leaf's record
+        inline: bool,
     },
-    // Branch node pointing to siblings
+    // Branch node pointing to siblings. 'data' caches the node's hash, so
+    // changing a child in place leaves it stale: build a new node with
+    // 'new_internal_node' instead.
+    #[non_exhaustive]
     Internal {
         index: u16,
         pos: u32,
@@ -50,8 +95,14 @@ impl Node {
             Node::Internal {
                 ref left,
                 ref right,
+                data,
                 ..
             } => {
+                // Set when the node is built. Decoded nodes don't have it
+                // until resolve sets it.
+                if *data != Digest::default() {
+                    return *data;
+                }
                 let lh = left.hash();
                 let rh = right.hash();
                 hash_internal(lh, rh)
@@ -59,16 +110,18 @@ impl Node {
         }
     }
 
-    // Used in resolve
+    /// Set the node's hash. Used in resolve, as decoded nodes don't carry
+    /// their own hash. Does nothing for the empty node.
     pub fn update_data_value(&mut self, h: Digest) {
         match self {
             Node::Hash { ref mut data, .. } => *data = h,
             Node::Leaf { ref mut data, .. } => *data = h,
             Node::Internal { ref mut data, .. } => *data = h,
-            _ => unimplemented!(),
+            Node::Empty {} => {}
         }
     }
 
+    /// The node's stored hash (zero for the empty node)
     pub fn get_data_value(&self) -> Digest {
         match self {
             Node::Leaf { data, .. } => *data,
@@ -80,17 +133,89 @@ impl Node {
 
     /// Set the position of the actual leaf value and it's position
     /// in the leaf node. Used to update the node when writing to storage.
+    /// Does nothing for other nodes.
     pub fn update_value_storage_location(&mut self, i: u16, p: u32) {
+        if let Node::Leaf {
+            ref mut vindex,
+            ref mut vpos,
+            ..
+        } = self
+        {
+            *vindex = i;
+            *vpos = p;
+        }
+    }
+
+    /// Attach the value to a leaf read from storage
+    pub fn update_value(&mut self, v: Vec<u8>) -> io::Result<()> {
+        match self {
+            Node::Leaf { ref mut value, .. } => {
+                *value = Some(v);
+                Ok(())
+            }
+            _ => Err(Error::other("Not a leaf")),
+        }
+    }
+
+    /// Mark a leaf, or a hash node pointing to one, as having its value in
+    /// the leaf's record
+    pub fn update_value_inline(&mut self, is_inline: bool) -> io::Result<()> {
+        match self {
+            Node::Leaf { ref mut inline, .. } => *inline = is_inline,
+            Node::Hash {
+                ref mut inline,
+                is_leaf: 1,
+                ..
+            } => *inline = is_inline,
+            _ => return Err(Error::other("Not a leaf")),
+        }
+        Ok(())
+    }
+
+    /// Is the leaf's value stored in the leaf's record?
+    pub fn has_inline_value(&self) -> bool {
         match self {
+            Node::Leaf { inline, .. } | Node::Hash { inline, .. } => *inline,
+            _ => false,
+        }
+    }
+
+    /// Record how the leaf value was stored when that isn't as is: its size
+    /// in storage and whether it's compressed. Used along with
+    /// `update_value_storage_location`. Does nothing for other nodes.
+    pub fn update_value_stored_size(&mut self, size: u16, is_compressed: bool) {
+        if let Node::Leaf {
+            ref mut vsize,
+            ref mut compressed,
+            ..
+        } = self
+        {
+            *vsize = size;
+            *compressed = is_compressed;
+        }
+    }
+
+    /// Is the leaf value stored compressed?
+    pub fn is_value_compressed(&self) -> bool {
+        matches!(
+            self,
             Node::Leaf {
-                ref mut vindex,
-                ref mut vpos,
+                compressed: true,
                 ..
-            } => {
-                *vindex = i;
-                *vpos = p;
             }
-            _ => unimplemented!(),
+        )
+    }
+
+    /// Return the file index, position and size of a leaf value in storage
+    pub fn get_value_storage_location(&self) -> (u16, u32, u16) {
+        match self {
+            Node::Leaf {
+                vindex,
+                vpos,
+                vsize,
+                ..
+            } => (*vindex, *vpos, *vsize),
+            _ => (0, 0, 0),
         }
     }
 
@@ -104,7 +229,8 @@ impl Node {
         }
     }
 
-    /// Update the position of the node in storage
+    /// Update the position of the node in storage. Does nothing for the
+    /// empty node.
     pub fn update_storage_location(&mut self, i: u16, p: u32) {
         match self {
             Node::Leaf {
@@ -131,26 +257,35 @@ impl Node {
                 *index = i;
                 *pos = p;
             }
-            _ => unimplemented!(),
+            Node::Empty {} => {}
         }
     }
 
     /// Convert the given node into a Hash node
     pub fn into_hash_node(self) -> Node {
-        match self {
+        self.to_hash_node()
+    }
+
+    /// A Hash node pointing to the given node, without taking it apart
+    pub fn to_hash_node(&self) -> Node {
+        match *self {
             Node::Internal { index, pos, .. } => Node::Hash {
                 index,
                 pos,
                 data: self.hash(),
                 is_leaf: 0,
+                inline: false,
             },
-            Node::Leaf { index, pos, .. } => Node::Hash {
+            Node::Leaf {
+                index, pos, inline, ..
+            } => Node::Hash {
                 index,
                 pos,
                 data: self.hash(),
                 is_leaf: 1,
+                inline,
             },
-            _ => self,
+            _ => self.clone(),
         }
     }
 
@@ -165,9 +300,22 @@ impl Node {
 
     /// Is the node an Empty (sentinal node)
     pub fn is_empty(&self) -> bool {
-        match self {
-            Node::Empty {} => true,
-            _ => false,
+        matches!(self, Node::Empty {})
+    }
+
+    /// Create the empty (sentinal) node
+    pub fn empty() -> Node {
+        Node::Empty {}
+    }
+
+    /// Create a hash node pointing to a leaf or internal node in storage
+    pub fn new_hash_node(index: u16, pos: u32, data: Digest, is_leaf: bool) -> Node {
+        Node::Hash {
+            index,
+            pos,
+            data,
+            is_leaf: is_leaf as u8,
+            inline: false,
         }
     }
 
@@ -188,6 +336,8 @@ impl Node {
             vindex: 0,
             vpos: 0,
             vsize: sz,
+            compressed: false,
+            inline: false,
         }
     }
 
@@ -196,7 +346,7 @@ impl Node {
         Node::Internal {
             index: 0,
             pos: 0,
-            data: Digest::default(),
+            data: hash_internal(left.hash(), right.hash()),
             left: left.into_boxed(),
             right: right.into_boxed(),
         }
@@ -211,9 +361,9 @@ impl Node {
     /// can determine the type of node when decoding raw bits.
     fn tag_pos_for_leaf_or_internal(pos: u32, is_leaf: bool) -> u32 {
         if is_leaf {
-            return pos * 2 + 1;
+            pos * 2 + 1
         } else {
-            return pos * 2;
+            pos * 2
         }
     }
 
@@ -223,50 +373,55 @@ impl Node {
     fn get_pos_tag(flagged_pos: u32) -> (u32, u8) {
         let is_leaf = (flagged_pos & 1) as u8;
         let pos = flagged_pos >> 1;
-        return (pos, is_leaf);
+        (pos, is_leaf)
+    }
+
+    /// Flag the encoded file index of a child that's a leaf with its value
+    /// inline. 'tagged' is the index as written.
+    fn tag_index(tagged: u16, child: &Node) -> io::Result<u16> {
+        let (index, _) = child.get_storage_location();
+        if index > MAX_FILE_INDEX {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("File index {} is too large for a node", index),
+            ));
+        }
+        if child.has_inline_value() {
+            Ok(tagged | INLINE_VALUE_FLAG)
+        } else {
+            Ok(tagged)
+        }
+    }
+
+    /// The reverse of `tag_index`: the index as written and whether the
+    /// child is a leaf with its value inline
+    fn get_index_tag(flagged_index: u16) -> (u16, bool) {
+        (
+            flagged_index & !INLINE_VALUE_FLAG,
+            flagged_index & INLINE_VALUE_FLAG != 0,
+        )
     }
 
     /// Encode a leaf or internal node for storage.
     /// Leaf: (40 bytes total)
-    ///  - u16 (2)  - value file index
+    ///  - u16 (2)  - value file index (the top bit flags a compressed value)
     ///  - u32 (4)  - value position
     ///  - u16 (2)  - value size
     ///  - (32)     - key hash
     ///
     /// Internal: (76 bytes total)
-    /// Left Node:
-    ///  - u16 (2)  - file index
-    ///  - u32 (4)  - file position
-    ///  - (32)     - hash
-    /// Right Node (same as above)
+    ///  - Left Node:
+    ///    - u16 (2)  - file index (the top bit flags a leaf with its value
+    ///      inline)
+    ///    - u32 (4)  - file position
+    ///    - (32)     - hash
+    ///  - Right Node (same as above)
     pub fn encode(&self) -> io::Result<Vec<u8>> {
         // Make the writer the largest capacity (INTERNAL)
         let mut writer = Vec::<u8>::with_capacity(INTERNAL_NODE_SIZE);
         match self {
-            Node::Leaf {
-                key,
-                vindex,
-                vpos,
-                vsize,
-                value,
-                ..
-            } => {
-                assert!(value.is_some(), "Leaf has no value!");
-                // Write the leaf node with the actual value information:
-
-                // leaf value file index
-                // Note: (* 2 + 1). this is used as a simple check for corruption
-                // during decode
-                writer.write_u16::<LittleEndian>(*vindex * 2 + 1)?;
-                // leaf value file position
-                writer.write_u32::<LittleEndian>(*vpos)?;
-                // the value size
-                writer.write_u16::<LittleEndian>(*vsize)?;
-                // the value key
-                writer.extend_from_slice(&key.0);
-
-                Ok(writer)
-            }
+            Node::Leaf { value: None, .. } => Err(Error::other("Leaf has no value!")),
+            Node::Leaf { .. } => self.encode_leaf(),
             Node::Internal { left, right, .. } => {
                 // Do the left node first...
                 // check to see if it's a leaf so we can encode it with the proper 'tag'
@@ -276,7 +431,7 @@ impl Node {
                 // index of file
                 // Note: (* 2). this is used as a simple check for corruption
                 // during decode
-                writer.write_u16::<LittleEndian>(lindex * 2)?;
+                writer.write_u16::<LittleEndian>(Node::tag_index(lindex * 2, left)?)?;
 
                 // pos - note the tagging
                 let left_pos = Node::tag_pos_for_leaf_or_internal(lpos, left_is_leaf);
@@ -291,7 +446,7 @@ impl Node {
 
                 // Write right node type
                 // index of file
-                writer.write_u16::<LittleEndian>(rindex)?;
+                writer.write_u16::<LittleEndian>(Node::tag_index(rindex, right)?)?;
                 // flags
                 let right_pos = Node::tag_pos_for_leaf_or_internal(rpos, right_is_leaf);
                 writer.write_u32::<LittleEndian>(right_pos)?;
@@ -300,38 +455,81 @@ impl Node {
 
                 Ok(writer)
             }
-            _ => Err(Error::new(ErrorKind::Other, "Only encode leaf/internal")),
+            _ => Err(Error::other("Only encode leaf/internal")),
+        }
+    }
+
+    /// Encode a leaf from where its value is stored, whether or not the value
+    /// is in memory
+    pub(crate) fn encode_leaf(&self) -> io::Result<Vec<u8>> {
+        match self {
+            Node::Leaf {
+                key,
+                vindex,
+                vpos,
+                vsize,
+                compressed,
+                ..
+            } => {
+                let mut writer = Vec::<u8>::with_capacity(LEAF_NODE_SIZE);
+                // Write the leaf node with the actual value information:
+
+                // leaf value file index
+                // Note: (* 2 + 1). this is used as a simple check for corruption
+                // during decode
+                if *vindex > MAX_FILE_INDEX {
+                    return Err(Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Value file index {} is too large for a leaf", vindex),
+                    ));
+                }
+                let mut tagged_vindex = *vindex * 2 + 1;
+                if *compressed {
+                    tagged_vindex |= COMPRESSED_VALUE_FLAG;
+                }
+                writer.write_u16::<LittleEndian>(tagged_vindex)?;
+                // leaf value file position
+                writer.write_u32::<LittleEndian>(*vpos)?;
+                // the value size
+                writer.write_u16::<LittleEndian>(*vsize)?;
+                // the value key
+                writer.extend_from_slice(&key.0);
+
+                Ok(writer)
+            }
+            _ => Err(Error::other("Not a leaf")),
         }
     }
 
     /// Decode bits from storage into the respective node.  Internal nodes contain
     /// hash nodes for the respective left and right nodes so we can properly navigate
     /// the tree.
-    pub fn decode(mut bits: Vec<u8>, is_leaf: bool) -> io::Result<Node> {
+    pub fn decode(bits: &[u8], is_leaf: bool) -> io::Result<Node> {
         if is_leaf {
-            assert_eq!(
-                bits.len(),
-                LEAF_NODE_SIZE,
-                "Decode: don't have enough bits for a leaf"
-            );
+            if bits.len() != LEAF_NODE_SIZE {
+                return Err(corrupt("Decode: wrong number of bits for a leaf"));
+            }
 
             // Grab the key from the end. We start at 8 as that's the end of the header
             // information.
-            let k = bits.split_off(8);
+            let (header, k) = bits.split_at(8);
 
             // Read the header information
-            let mut rdr = Cursor::new(bits);
-            let shifted_vindex = rdr.read_u16::<LittleEndian>()?;
-            assert!(shifted_vindex & 1 == 1, "Corrupt database @ leaf");
+            let mut rdr = Cursor::new(header);
+            let tagged_vindex = rdr.read_u16::<LittleEndian>()?;
+            let compressed = tagged_vindex & COMPRESSED_VALUE_FLAG != 0;
+            let shifted_vindex = tagged_vindex & !COMPRESSED_VALUE_FLAG;
+            if shifted_vindex & 1 != 1 {
+                return Err(corrupt("Corrupt database @ leaf"));
+            }
             let vindex = shifted_vindex >> 1;
 
             let vpos = rdr.read_u32::<LittleEndian>()?;
             let vsize = rdr.read_u16::<LittleEndian>()?;
 
             // Extract the key
-            assert!(k.len() == 32);
             let mut keybits: [u8; 32] = Default::default();
-            keybits.copy_from_slice(&k);
+            keybits.copy_from_slice(k);
 
             Ok(Node::Leaf {
                 pos: 0,
@@ -342,23 +540,23 @@ impl Node {
                 vindex,
                 vpos,
                 vsize,
+                compressed,
+                inline: false,
             })
         } else {
-            assert_eq!(
-                bits.len(),
-                INTERNAL_NODE_SIZE,
-                "Decode: don't have enough bits for an internal node"
-            );
+            if bits.len() != INTERNAL_NODE_SIZE {
+                return Err(corrupt("Decode: wrong number of bits for an internal node"));
+            }
 
             // Parse internal node
             let mut offset = 0;
 
-            let shifted_left_index = LittleEndian::read_u16(&bits[offset..]);
+            let (shifted_left_index, left_inline) =
+                Node::get_index_tag(LittleEndian::read_u16(&bits[offset..]));
             offset += 2;
-            assert!(
-                shifted_left_index & 1 == 0,
-                "Corrupt database @ internal node"
-            );
+            if shifted_left_index & 1 != 0 {
+                return Err(corrupt("Corrupt database @ internal node"));
+            }
             let left_index = shifted_left_index >> 1;
 
             let leftnode = if left_index != 0 {
@@ -374,13 +572,15 @@ impl Node {
                     index: left_index,
                     data: Digest::from(left_hash),
                     is_leaf: left_leaf_flag,
+                    inline: left_inline,
                 }
             } else {
                 offset += 4 + 32;
                 Node::Empty {}
             };
 
-            let right_index = LittleEndian::read_u16(&bits[offset..]);
+            let (right_index, right_inline) =
+                Node::get_index_tag(LittleEndian::read_u16(&bits[offset..]));
             offset += 2;
 
             let rightnode = if right_index != 0 {
@@ -394,6 +594,7 @@ impl Node {
                     index: right_index,
                     data: Digest::from(right_hash),
                     is_leaf: right_leaf_flag,
+                    inline: right_inline,
                 }
             } else {
                 Node::Empty {}
@@ -410,6 +611,11 @@ impl Node {
     }
 }
 
+/// Error for bits that don't decode to a node
+fn corrupt(what: &str) -> Error {
+    Error::new(io::ErrorKind::InvalidData, what)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -430,11 +636,13 @@ mod tests {
             vindex: 1,
             vpos: 500,
             vsize: sz,
+            compressed: false,
+            inline: false,
         };
         let bits = leaf.encode();
         assert!(bits.is_ok());
 
-        let back = Node::decode(bits.unwrap(), true);
+        let back = Node::decode(&bits.unwrap(), true);
         assert!(back.is_ok());
 
         let r = match back.unwrap() {
@@ -455,6 +663,22 @@ mod tests {
         };
         assert!(r);
 
+        let mut compressed = leaf.clone();
+        compressed.update_value_stored_size(5, true);
+        let back = Node::decode(&compressed.encode().unwrap(), true).unwrap();
+        assert!(back.is_value_compressed());
+        assert_eq!((1, 500, 5), back.get_value_storage_location());
+
+        // Any larger and the index runs into the compressed flag
+        let mut last = compressed.clone();
+        last.update_value_storage_location(MAX_FILE_INDEX, 500);
+        let back = Node::decode(&last.encode().unwrap(), true).unwrap();
+        assert!(back.is_value_compressed());
+        assert_eq!((MAX_FILE_INDEX, 500, 5), back.get_value_storage_location());
+        let mut past = leaf.clone();
+        past.update_value_storage_location(MAX_FILE_INDEX + 1, 500);
+        assert!(past.encode().is_err());
+
         let internal = Node::Internal {
             index: 0,
             pos: 0,
@@ -465,7 +689,7 @@ mod tests {
         let ibits = internal.encode();
         assert!(ibits.is_ok());
 
-        let iback = Node::decode(ibits.unwrap(), false);
+        let iback = Node::decode(&ibits.unwrap(), false);
         assert!(iback.is_ok());
 
         let r1 = match iback.unwrap() {
@@ -485,4 +709,80 @@ mod tests {
         let shouldnot = Node::Empty {}.encode();
         assert!(shouldnot.is_err());
     }
+
+    #[test]
+    fn test_internal_hash_cached() {
+        let mut left = Node::new_leaf_node(hash(b"name-1"), "value-1");
+        let mut right = Node::new_leaf_node(hash(b"name-2"), "value-2");
+        left.update_storage_location(1, 0);
+        right.update_storage_location(1, 40);
+        let expected = hash_internal(left.hash(), right.hash());
+
+        let internal = Node::new_internal_node(left, right);
+        assert_eq!(expected, internal.get_data_value());
+        assert_eq!(expected, internal.hash());
+
+        // Decoded nodes don't carry it, so it's computed from the children
+        let decoded = Node::decode(&internal.encode().unwrap(), false).unwrap();
+        assert_eq!(Digest::default(), decoded.get_data_value());
+        assert_eq!(expected, decoded.hash());
+    }
+
+    #[test]
+    fn test_decode_corrupt() {
+        let invalid = |r: io::Result<Node>| r.unwrap_err().kind() == io::ErrorKind::InvalidData;
+        let leaf = Node::new_leaf_node(hash(b"name-1"), "value-1");
+        let bits = leaf.encode().unwrap();
+        assert!(invalid(Node::decode(&bits[..LEAF_NODE_SIZE - 1], true)));
+        // Value index without its tag
+        let mut untagged = bits.clone();
+        untagged[0] &= !1;
+        assert!(invalid(Node::decode(&untagged, true)));
+
+        let internal = Node::new_internal_node(leaf, Node::empty());
+        let bits = internal.encode().unwrap();
+        assert!(invalid(Node::decode(&bits, true)));
+        assert!(invalid(Node::decode(&bits[1..], false)));
+        let mut odd = bits.clone();
+        odd[0] |= 1;
+        assert!(invalid(Node::decode(&odd, false)));
+
+        // Setters leave nodes they don't apply to alone
+        let mut empty = Node::empty();
+        empty.update_data_value(hash(b"name-1"));
+        empty.update_storage_location(1, 0);
+        empty.update_value_storage_location(1, 0);
+        empty.update_value_stored_size(5, true);
+        assert_eq!(Node::empty(), empty);
+    }
+
+    #[test]
+    fn test_inline_value_flag() {
+        let mut left = Node::new_leaf_node(hash(b"name-1"), "value-1");
+        let mut right = Node::new_leaf_node(hash(b"name-2"), "value-2");
+        left.update_storage_location(MAX_FILE_INDEX, 0);
+        right.update_storage_location(MAX_FILE_INDEX, 80);
+        right.update_value_inline(true).unwrap();
+        let internal = Node::new_internal_node(left.into_hash_node(), right.into_hash_node());
+
+        // The flag survives in the pointers, without touching the index
+        match Node::decode(&internal.encode().unwrap(), false).unwrap() {
+            Node::Internal { left, right, .. } => {
+                assert!(!left.has_inline_value());
+                assert!(right.has_inline_value());
+                assert_eq!((MAX_FILE_INDEX, 0), left.get_storage_location());
+                assert_eq!((MAX_FILE_INDEX, 80), right.get_storage_location());
+            }
+            _ => panic!("Not an internal node"),
+        }
+
+        // Only leaves have values
+        assert!(internal.clone().update_value(vec![]).is_err());
+        assert!(internal.into_hash_node().update_value_inline(true).is_err());
+
+        let mut past = Node::new_leaf_node(hash(b"name-3"), "value-3");
+        past.update_storage_location(MAX_FILE_INDEX + 1, 0);
+        let internal = Node::new_internal_node(past, Node::empty());
+        assert!(internal.encode().is_err());
+    }
 }
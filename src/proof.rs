@@ -17,7 +17,7 @@ pub struct Proof {
     pub value: Option<Vec<u8>>,
 }
 
-impl<'a> Default for Proof {
+impl Default for Proof {
     fn default() -> Self {
         Proof {
             proof_type: ProofType::Deadend,
@@ -81,18 +81,15 @@ impl Proof {
         };
 
         let mut next = leaf;
-        let mut depth = self.depth() - 1;
+        let mut depth = self.depth();
 
         for n in self.node_hashes.iter().rev() {
+            depth -= 1;
             if has_bit(&hashed_key, depth) {
                 next = hash_internal(*n, next)
             } else {
                 next = hash_internal(next, *n)
             }
-
-            if depth > 0 {
-                depth -= 1;
-            }
         }
 
         if next != root_hash {
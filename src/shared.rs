@@ -0,0 +1,84 @@
+//! A trie handle for one writer and many concurrent readers.
+//!
+//! `SharedTrie` publishes the root of every commit. Readers take a read only
+//! view of the last published root and resolve nodes through the store
+//! without holding any lock of the trie, so a writer that is in the middle
+//! of a commit never blocks them. Changes only become visible to readers once
+//! `commit` returns.
+use super::errors::Result;
+use super::hasher::Digest;
+use super::node::Node;
+use super::proof::Proof;
+use super::trie::UrkelTrie;
+use super::urkeldb::Store;
+use super::{CommitInfo, TrieStore};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Wrap it in an `Arc` to share it between threads
+pub struct SharedTrie<S: TrieStore = Store> {
+    store: Arc<S>,
+    // Root of the last commit, a hash node
+    root: RwLock<Box<Node>>,
+    writer: Mutex<UrkelTrie<S>>,
+}
+
+impl<S: TrieStore> SharedTrie<S> {
+    /// Share the tree. Readers start at the store's last committed root, so
+    /// uncommitted changes in 'trie' are only visible after the next commit.
+    pub fn new(trie: UrkelTrie<S>) -> Self {
+        SharedTrie {
+            store: trie.shared_store(),
+            root: RwLock::new(trie.committed_root()),
+            writer: Mutex::new(trie),
+        }
+    }
+
+    /// A read only tree at the last committed root. It keeps reading that
+    /// root even if the writer commits again.
+    pub fn reader(&self) -> UrkelTrie<S> {
+        let root = self.root.read().unwrap().clone();
+        UrkelTrie::view(Arc::clone(&self.store), root)
+    }
+
+    /// Hash of the last committed root
+    pub fn get_root_hash(&self) -> Digest {
+        self.root.read().unwrap().hash()
+    }
+
+    /// Get a key as of the last commit
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.reader().get(key)
+    }
+
+    /// Prove a key against the last committed root
+    pub fn prove(&self, key: &[u8]) -> Result<Proof> {
+        self.reader().prove(key)
+    }
+
+    /// Insert or update a key in the writer's tree. Returns the value it
+    /// replaced in the writer's tree, committed or not.
+    pub fn set<T>(&self, key: &[u8], value: T) -> Result<Option<Vec<u8>>>
+    where
+        T: Into<Vec<u8>>,
+    {
+        self.writer.lock().unwrap().set(key, value)
+    }
+
+    /// Remove a key from the writer's tree. Returns the removed value.
+    pub fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.writer.lock().unwrap().remove(key)
+    }
+
+    /// Commit the writer's tree and publish the new root to readers
+    pub fn commit(&self) -> Result<CommitInfo> {
+        self.commit_with(&[])
+    }
+
+    /// `commit` with metadata for the new root, see `UrkelTrie::commit_with`
+    pub fn commit_with(&self, meta: &[u8]) -> Result<CommitInfo> {
+        let mut writer = self.writer.lock().unwrap();
+        let info = writer.commit_with(meta)?;
+        *self.root.write().unwrap() = writer.committed_root();
+        Ok(info)
+    }
+}
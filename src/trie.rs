@@ -1,60 +1,310 @@
+use super::cache::CacheStats;
+use super::diff::Diff;
+use super::errors::{Error, Result};
 use super::has_bit;
 use super::hasher::KEY_SIZE;
 use super::hasher::{hash, hash_leaf_value, Digest};
+use super::journal::JournalEntry;
+use super::kvstore::{KvBackend, KvOp, KvStore};
 use super::node::Node;
 use super::proof::{Proof, ProofType};
-use super::urkeldb::Store;
-use super::TrieStore;
-use std::sync::{Arc, RwLock};
+use super::urkeldb::{Options, Store};
+use super::{CommitInfo, RootInfo, TrieStore};
+use std::collections::BTreeMap;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 //use log::{info, trace, warn};
 
+/// A single change in `UrkelTrie::apply_batch`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Put(Vec<u8>),
+    Delete,
+}
+
 //#[derive(Clone)]
-pub struct UrkelTrie<'db> {
+pub struct UrkelTrie<S: TrieStore = Store> {
     root: Option<Box<Node>>,
-    store: Arc<RwLock<Store<'db>>>,
+    store: Arc<S>,
+    read_only: bool,
+    // Journal entries for the keys changed since the last commit
+    pending: BTreeMap<[u8; 32], JournalEntry>,
 }
 
-impl<'db> UrkelTrie<'db> {
+impl UrkelTrie<Store> {
     /// Create a tree. Opens the database and attemps to load the last
     /// root if any. Otherwise starts with an empty tree node.
-    pub fn new(dir: &'db str) -> Self {
-        let db = Store::open(dir).expect("Failed to open store");
-        let mut tree = UrkelTrie {
-            root: None,
-            store: Arc::new(RwLock::new(db)),
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        UrkelTrie::open(dir).expect("Failed to open store")
+    }
+
+    /// Same as `new` but returns an error instead of panicking. Fails with
+    /// `Error::Locked` if another writer already has the directory open.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        UrkelTrie::open_with_options(dir, Options::default())
+    }
+
+    /// Open an existing tree for reading only. Fails if the directory or the
+    /// log files don't exist. No files are created or written, and every
+    /// mutating method returns `Error::ReadOnly`.
+    pub fn open_read_only<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let options = Options {
+            read_only: true,
+            ..Options::default()
         };
+        UrkelTrie::open_with_options(dir, options)
+    }
+
+    /// Open a tree with the given store options
+    pub fn open_with_options<P: AsRef<Path>>(dir: P, options: Options) -> Result<Self> {
+        let db = Store::open(dir, &options)?;
+        Ok(UrkelTrie::from_store(db, options.read_only))
+    }
+
+    /// Hit/miss counters of the store's node cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.store.cache_stats()
+    }
+
+    /// Sync commits the `SyncPolicy` left unsynced to disk
+    pub fn sync(&self) -> Result<()> {
+        self.check_writable()?;
+        Ok(self.store.sync()?)
+    }
+
+    /// Run 'f', which can commit any number of times, and sync all of its
+    /// commits to disk once at the end whatever the `SyncPolicy`. Commits
+    /// that returned are synced even if 'f' then fails. Groups can nest;
+    /// the outermost one syncs.
+    pub fn group_commit<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        self.check_writable()?;
+        if self.store.set_group(true) {
+            // Nested: the outermost group syncs
+            return f(self);
+        }
+        let group = CommitGroup(Arc::clone(&self.store));
+        let result = f(self);
+        drop(group);
+        let synced = self.store.sync();
+        let value = result?;
+        synced?;
+        Ok(value)
+    }
+}
+
+/// Ends a commit group when dropped, so a panic in 'f' doesn't leave every
+/// later commit unsynced
+struct CommitGroup(Arc<Store>);
+
+impl Drop for CommitGroup {
+    fn drop(&mut self) {
+        self.0.set_group(false);
+    }
+}
+
+impl<B: KvBackend> UrkelTrie<KvStore<B>> {
+    /// Commit like `commit_with`, applying 'ops' to the backend in the same
+    /// batch as the trie. See `KvStore::commit_with_ops`.
+    pub fn commit_with_ops(&mut self, meta: &[u8], ops: Vec<KvOp>) -> Result<CommitInfo> {
+        self.commit_to(|store, root, _| store.commit_with_ops(root, meta, ops))
+    }
+
+    /// The backend the tree is stored in, to read your own keys or compact
+    pub fn backend(&self) -> &B {
+        self.store.backend()
+    }
+}
+
+impl<S: TrieStore> UrkelTrie<S> {
+    /// Create a tree on top of the given store, starting from the store's
+    /// last committed root if any.
+    pub fn with_store(store: S) -> Self {
+        UrkelTrie::from_store(store, false)
+    }
+
+    fn from_store(db: S, read_only: bool) -> Self {
         // Attempt to load the last root
-        tree.root = match tree.store.read().unwrap().get_root() {
-            Ok(root) => Some(root),
-            Err(_) => Some(Box::new(Node::Empty {})),
+        let root = match db.get_root() {
+            Ok(root) => root,
+            Err(_) => Box::new(Node::Empty {}),
         };
-        tree
+        UrkelTrie {
+            root: Some(root),
+            store: Arc::new(db),
+            read_only,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// A read only tree at 'root' on a shared store
+    pub(crate) fn view(store: Arc<S>, root: Box<Node>) -> Self {
+        UrkelTrie {
+            root: Some(root),
+            store,
+            read_only: true,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn shared_store(&self) -> Arc<S> {
+        Arc::clone(&self.store)
     }
 
-    pub fn set<T>(&mut self, key: &[u8], value: T)
+    /// The last root committed to the store, or an empty node
+    pub(crate) fn committed_root(&self) -> Box<Node> {
+        self.store
+            .get_root()
+            .unwrap_or_else(|_| Node::empty().into_boxed())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Insert or update a key. Returns the value it replaced, if any. If
+    /// reading from the store fails, uncommitted changes are dropped and the
+    /// tree goes back to the last committed root.
+    pub fn set<T>(&mut self, key: &[u8], value: T) -> Result<Option<Vec<u8>>>
     where
         T: Into<Vec<u8>>,
     {
+        self.check_writable()?;
         let hashed_key = hash(key);
-        match self.root.take() {
-            Some(n) => self.root = self.add_child(n, hashed_key, value.into()),
-            None => self.root = Some(Node::new_leaf_node(hashed_key, value).into_boxed()),
+        let root = match self.root.take() {
+            Some(n) => self
+                .add_child(n, hashed_key, value.into(), None)
+                .map(|(r, set)| (r, set.flatten())),
+            None => Ok((Node::new_leaf_node(hashed_key, value).into_boxed(), None)),
+        };
+        self.restore_root(root)
+    }
+
+    /// Set the key only if it isn't in the tree yet. Returns whether it was
+    /// inserted.
+    pub fn insert_if_absent<T>(&mut self, key: &[u8], value: T) -> Result<bool>
+    where
+        T: Into<Vec<u8>>,
+    {
+        self.cas(key, None, value)
+    }
+
+    /// Compare and swap: set the key to 'new' only if its current value is
+    /// 'expected' (`None` meaning the key isn't in the tree). Returns false,
+    /// without touching the tree, if the current value doesn't match.
+    pub fn cas<T>(&mut self, key: &[u8], expected: Option<&[u8]>, new: T) -> Result<bool>
+    where
+        T: Into<Vec<u8>>,
+    {
+        self.check_writable()?;
+        let root = self
+            .root
+            .take()
+            .unwrap_or_else(|| Node::empty().into_boxed());
+        let root = self.add_child(root, hash(key), new.into(), Some(expected));
+        Ok(self.restore_root(root)?.is_some())
+    }
+
+    /// Put back the root taken by a mutating method, passing on the rest of
+    /// its result. If the operation failed the tree is reset to the last
+    /// committed root (or an empty tree if the store can't give it).
+    fn restore_root<T>(&mut self, result: Result<(Box<Node>, T)>) -> Result<T> {
+        match result {
+            Ok((r, t)) => {
+                self.root = Some(r);
+                Ok(t)
+            }
+            Err(e) => {
+                self.root = Some(self.committed_root());
+                self.pending.clear();
+                Err(e)
+            }
+        }
+    }
+
+    /// Note a change to 'key' for the journal. Only the first change since
+    /// the last commit sees the committed leaf, so later ones keep it.
+    fn record(&mut self, key: Digest, previous: Option<&Node>, removed: bool) {
+        self.pending
+            .entry(key.0)
+            .or_insert_with(|| JournalEntry::new(key, previous, removed))
+            .removed = removed;
+    }
+
+    /// Resolve a hash node through the store
+    pub(crate) fn resolve(&self, node: Node) -> Result<Box<Node>> {
+        Ok(self.store.resolve(node)?)
+    }
+
+    /// The value of a leaf. Either still in memory or read from the store
+    pub(crate) fn leaf_value(&self, leaf: &Node) -> Result<Vec<u8>> {
+        match leaf {
+            Node::Leaf {
+                value: Some(ref v), ..
+            } => Ok(v.clone()),
+            _ => Ok(self.store.get(leaf)?),
         }
     }
 
-    fn add_child(&self, mut root: Box<Node>, nkey: Digest, value: Vec<u8>) -> Option<Box<Node>> {
+    /// Returns the new root and, if the key was set, the value it replaced.
+    /// With 'expected', the key is only set if its current value matches
+    /// (see `cas`); otherwise the tree is put back as it was.
+    fn add_child(
+        &mut self,
+        mut root: Box<Node>,
+        nkey: Digest,
+        value: Vec<u8>,
+        expected: Option<Option<&[u8]>>,
+    ) -> Result<(Box<Node>, Added)> {
         let mut depth = 0;
         let mut nodes = Vec::<Node>::new();
         let leaf_hash = hash_leaf_value(nkey, value.as_slice());
+        let mut previous = None;
+        // To put the path back if 'expected' doesn't match or the value is
+        // unchanged
+        let mut visited = Vec::<Visited>::new();
+        let mut unresolved = None;
 
         loop {
             match *root {
-                Node::Empty {} => break,
-                Node::Hash { .. } => root = self.store.read().unwrap().resolve(*root),
+                Node::Empty {} => {
+                    if let Some(Some(_)) = expected {
+                        let root = rebuild_path(*root, unresolved, nodes, visited, nkey);
+                        return Ok((root, None));
+                    }
+                    break;
+                }
+                Node::Hash { .. } => {
+                    unresolved = Some((*root).clone());
+                    root = self.resolve(*root)?
+                }
                 Node::Leaf { key, data, .. } => {
+                    if let Some(expected) = expected {
+                        // Compare hashes, so the value isn't read
+                        let current = if nkey == key { Some(data) } else { None };
+                        if current != expected.map(|v| hash_leaf_value(nkey, v)) {
+                            let root = rebuild_path(*root, unresolved, nodes, visited, nkey);
+                            return Ok((root, None));
+                        }
+                    }
+
                     if nkey == key {
+                        // Same value: keep the existing path, storage
+                        // locations and all, so the commit has nothing to write
                         if leaf_hash == data {
-                            return Some(root);
+                            let root = rebuild_path(*root, unresolved, nodes, visited, nkey);
+                            return Ok((root, Some(Some(value))));
+                        } else {
+                            previous = Some(self.leaf_value(&root)?);
+                            self.record(nkey, Some(&root), false);
                         }
                         break;
                     }
@@ -68,8 +318,20 @@ impl<'db> UrkelTrie<'db> {
                     depth += 1;
                     break;
                 }
-                Node::Internal { left, right, .. } => {
+                Node::Internal {
+                    left,
+                    right,
+                    index,
+                    pos,
+                    data,
+                } => {
                     assert_ne!(depth, KEY_SIZE);
+                    visited.push(Visited {
+                        unresolved: unresolved.take(),
+                        index,
+                        pos,
+                        data,
+                    });
                     if has_bit(&nkey, depth) {
                         nodes.push(*left);
                         root = right;
@@ -82,6 +344,10 @@ impl<'db> UrkelTrie<'db> {
             }
         }
 
+        if previous.is_none() {
+            self.record(nkey, None, false);
+        }
+
         // Start with a leaf of the new K/V
         let mut new_root = Node::new_leaf_node(nkey, value);
 
@@ -95,7 +361,7 @@ impl<'db> UrkelTrie<'db> {
             }
         }
         // return the new root
-        Some(Box::new(new_root))
+        Ok((Box::new(new_root), Some(previous)))
     }
 
     /// Get the root hash
@@ -103,27 +369,71 @@ impl<'db> UrkelTrie<'db> {
         self.root.as_ref().map_or(Digest::zero(), |r| r.hash())
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+    /// The changes that turn the tree at 'old_root' into the tree at
+    /// 'new_root'. Either can be the current (uncommitted) root, a root the
+    /// store still has, or the zero hash for an empty tree. Fails with
+    /// `Error::UnknownRoot` otherwise.
+    ///
+    /// `Store` only looks for roots in its current log file, so roots
+    /// committed to earlier log files count as unknown.
+    pub fn diff(&self, old_root: Digest, new_root: Digest) -> Result<Diff<'_, S>> {
+        let old = self.find_root(old_root)?;
+        let new = self.find_root(new_root)?;
+        Ok(Diff::new(self, old, new))
+    }
+
+    fn find_root(&self, root_hash: Digest) -> Result<Node> {
+        if root_hash == Digest::zero() {
+            return Ok(Node::empty());
+        }
+        if let Some(ref root) = self.root {
+            if root.hash() == root_hash {
+                return Ok((**root).clone());
+            }
+        }
+        match self.store.find_root(root_hash)? {
+            Some(root) => Ok(*root),
+            None => Err(Error::UnknownRoot(root_hash)),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.find_leaf(hash(key))? {
+            // If the value is !None return it. Otherwise go to storage...
+            Some(leaf) => self.leaf_value(&leaf).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Is the key in the tree? Only reads nodes, never the value.
+    pub fn contains(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.find_leaf(hash(key))?.is_some())
+    }
+
+    /// The hash of the key's leaf, without reading the value. This is the
+    /// leaf hash, `hasher::hash_leaf_value(hash(key), value)`, not a hash
+    /// of the value alone. It changes whenever the value does and can be
+    /// checked against an expected value without fetching the stored one.
+    pub fn get_leaf_hash(&self, key: &[u8]) -> Result<Option<Digest>> {
+        Ok(self.find_leaf(hash(key))?.map(|leaf| leaf.hash()))
+    }
+
+    /// Walk down to the leaf for 'nkey', if it's in the tree. Only nodes
+    /// resolved from the store are copied.
+    fn find_leaf(&self, nkey: Digest) -> Result<Option<Node>> {
         let mut depth = 0;
-        let nkey = hash(key);
-        let mut current = self.root.clone().unwrap();
+        let mut resolved: Box<Node>;
+        let mut current = match self.root {
+            Some(ref root) => root.as_ref(),
+            None => return Ok(None),
+        };
         loop {
-            match *current {
-                Node::Hash { .. } => current = self.store.read().unwrap().resolve(*current),
-                Node::Leaf {
-                    key,
-                    vindex,
-                    vpos,
-                    vsize,
-                    value,
-                    ..
-                } => {
-                    if nkey != key {
-                        return None;
-                    }
-                    // If the value is !None return it. Otherwise go to storage...
-                    return value.or_else(|| self.store.read().unwrap().get(vindex, vpos, vsize));
+            match current {
+                Node::Hash { .. } => {
+                    resolved = self.resolve(current.clone())?;
+                    current = &resolved;
                 }
+                Node::Leaf { key, .. } if *key == nkey => return Ok(Some(current.clone())),
                 Node::Internal { left, right, .. } => {
                     if has_bit(&nkey, depth) {
                         current = right;
@@ -132,44 +442,79 @@ impl<'db> UrkelTrie<'db> {
                     }
                     depth += 1;
                 }
-                _ => return None,
+                _ => return Ok(None),
             }
         }
     }
 
-    pub fn remove(&mut self, key: &[u8]) {
+    /// Remove a key. Returns the removed value, if the key was there. Fails
+    /// the same way as `set`.
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check_writable()?;
         let hashed_key = hash(key);
-        match self.root.take() {
-            Some(n) => self.root = self.remove_child(n, hashed_key),
-            None => self.root = Some(Node::Empty {}.into_boxed()),
-        }
+        let root = match self.root.take() {
+            Some(n) => self.remove_child(*n, hashed_key),
+            None => Ok((Node::Empty {}.into_boxed(), None)),
+        };
+        self.restore_root(root)
     }
 
-    fn remove_child(&self, mut root: Box<Node>, nkey: Digest) -> Option<Box<Node>> {
+    /// Returns the new root and the removed value, if any
+    fn remove_child(
+        &mut self,
+        mut root: Node,
+        nkey: Digest,
+    ) -> Result<(Box<Node>, Option<Vec<u8>>)> {
         let mut depth = 0;
         let mut nodes = Vec::<Node>::new();
+        let removed;
+        // To put the path back if the key isn't there
+        let mut visited = Vec::<Visited>::new();
+        let mut unresolved = None;
         loop {
-            match *root {
-                Node::Empty {} => return Some(root),
-                Node::Hash { .. } => root = self.store.read().unwrap().resolve(*root),
-                Node::Internal { left, right, .. } => {
+            match root {
+                // Not found: put the path back as it was
+                Node::Empty {} => {
+                    let root = rebuild_path(root, unresolved, nodes, visited, nkey);
+                    return Ok((root, None));
+                }
+                Node::Hash { .. } => {
+                    unresolved = Some(root.clone());
+                    root = *self.resolve(root)?
+                }
+                Node::Internal {
+                    left,
+                    right,
+                    index,
+                    pos,
+                    data,
+                } => {
                     assert_ne!(depth, KEY_SIZE);
+                    visited.push(Visited {
+                        unresolved: unresolved.take(),
+                        index,
+                        pos,
+                        data,
+                    });
 
                     if has_bit(&nkey, depth) {
                         nodes.push(*left);
-                        root = right;
+                        root = *right;
                     } else {
                         nodes.push(*right);
-                        root = left
+                        root = *left
                     }
                     depth += 1;
                 }
                 Node::Leaf { key, .. } => {
                     if nkey != key {
-                        return Some(root);
+                        let root = rebuild_path(root, unresolved, nodes, visited, nkey);
+                        return Ok((root, None));
                     }
+                    removed = Some(self.leaf_value(&root)?);
+                    self.record(nkey, Some(&root), true);
                     if depth == 0 {
-                        return Some(Node::Empty {}.into_boxed());
+                        return Ok((Node::Empty {}.into_boxed(), removed));
                     }
 
                     let n = nodes[depth - 1].clone();
@@ -186,16 +531,16 @@ impl<'db> UrkelTrie<'db> {
                             depth -= 1;
                         }
 
-                        root = n.into_boxed();
+                        root = n;
                     } else {
-                        root = Node::into_boxed(Node::Empty {});
+                        root = Node::Empty {};
                     }
                     break;
                 }
             }
         }
 
-        let mut new_root = *root;
+        let mut new_root = root;
         for n in nodes.into_iter().rev() {
             depth -= 1;
             if has_bit(&nkey, depth) {
@@ -205,35 +550,187 @@ impl<'db> UrkelTrie<'db> {
             }
         }
 
-        Some(new_root.into_boxed())
+        Ok((new_root.into_boxed(), removed))
+    }
+
+    /// Apply a batch of puts and deletes in one pass over the tree. Ends up
+    /// with the same tree as calling `set`/`remove` for each op in order
+    /// (the last op for a key wins), but each node on a shared path is only
+    /// visited and rebuilt once. Fails the same way as `set`: if a read from
+    /// the store fails part way, the batch and every other uncommitted
+    /// change are dropped, and the tree is back at the last committed root.
+    pub fn apply_batch<I, K>(&mut self, ops: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, Op)>,
+        K: AsRef<[u8]>,
+    {
+        self.check_writable()?;
+        let mut ops: Vec<(Digest, Op)> = ops
+            .into_iter()
+            .map(|(k, op)| (hash(k.as_ref()), op))
+            .collect();
+        // Sorting by key groups the ops by subtree. Reversing first means the
+        // (stable) sort puts the last op for a key first, and dedup keeps it.
+        ops.reverse();
+        ops.sort_by_key(|(key, _)| key.0);
+        ops.dedup_by(|a, b| a.0 == b.0);
+
+        self.apply_hashed(ops)
+    }
+
+    /// `apply_batch` with the keys already hashed, sorted and deduped
+    fn apply_hashed(&mut self, ops: Vec<(Digest, Op)>) -> Result<()> {
+        let root = self.root.take().map_or(Node::empty(), |n| *n);
+        let root = self.apply_ops(root, ops, 0).map(|r| (r.into_boxed(), ()));
+        self.restore_root(root)
+    }
+
+    /// Call 'f' with each committed root and its metadata, newest first,
+    /// until it returns false. Going back far reads a lot of the log, so
+    /// stop as soon as possible.
+    pub fn walk_history<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&RootInfo) -> bool,
+    {
+        Ok(self.store.walk_roots(&mut |info| f(&info))?)
+    }
+
+    /// The metadata 'root_hash' was committed with, if the store still has
+    /// the root. If it was committed more than once, the latest.
+    pub fn root_meta(&self, root_hash: Digest) -> Result<Option<Vec<u8>>> {
+        Ok(self.store.root_info(root_hash)?.map(|info| info.meta))
+    }
+
+    /// The journal kept for the commit of 'root_hash': the keys it set or
+    /// removed, in key order, with the leaves they had before. If the same
+    /// root was committed more than once, it's the latest commit's. None if
+    /// the store doesn't keep journals or has none for that root.
+    pub fn journal(&self, root_hash: Digest) -> Result<Option<Vec<JournalEntry>>> {
+        Ok(self.store.get_journal(root_hash)?)
+    }
+
+    /// Undo the commit of 'root_hash' by putting back the previous values of
+    /// the keys it changed, without diffing against the parent root. Applied
+    /// to the tree at 'root_hash' this gets back the parent root; changes
+    /// made to other keys since are kept. Like any change it needs a
+    /// `commit`. Fails with `Error::NoJournal` if the store has no journal
+    /// for the root.
+    pub fn revert(&mut self, root_hash: Digest) -> Result<()> {
+        self.check_writable()?;
+        let journal = match self.store.get_journal(root_hash)? {
+            Some(journal) => journal,
+            None => return Err(Error::NoJournal(root_hash)),
+        };
+        // Entries are in key order, which is what 'apply_ops' expects
+        let mut ops = Vec::with_capacity(journal.len());
+        for entry in journal {
+            let op = match entry.previous {
+                Some(ref leaf) => Op::Put(self.store.get(leaf)?),
+                None => Op::Delete,
+            };
+            ops.push((entry.key, op));
+        }
+        self.apply_hashed(ops)
+    }
+
+    /// Apply 'ops' (sorted, one per key, all below 'root') to the subtree
+    fn apply_ops(&mut self, root: Node, mut ops: Vec<(Digest, Op)>, depth: usize) -> Result<Node> {
+        if ops.is_empty() {
+            return Ok(root);
+        }
+        match root {
+            Node::Empty {} => {
+                for (key, op) in &ops {
+                    if let Op::Put(_) = op {
+                        self.record(*key, None, false);
+                    }
+                }
+                Ok(build_subtree(new_leaves(ops), depth))
+            }
+            Node::Hash { .. } => self.apply_ops(*self.resolve(root)?, ops, depth),
+            Node::Leaf { key, data, .. } => {
+                let mut existing = Some(root);
+                let mut leaves = Vec::with_capacity(ops.len() + 1);
+                for (nkey, op) in ops {
+                    match op {
+                        Op::Put(value) => {
+                            let leaf = Node::new_leaf_node(nkey, value);
+                            // Same value: keep the existing leaf
+                            if nkey == key && leaf.hash() == data {
+                                continue;
+                            }
+                            let previous = if nkey == key { existing.take() } else { None };
+                            self.record(nkey, previous.as_ref(), false);
+                            leaves.push(leaf);
+                        }
+                        Op::Delete if nkey == key => {
+                            let previous = existing.take();
+                            self.record(nkey, previous.as_ref(), true);
+                        }
+                        Op::Delete => {}
+                    }
+                }
+                match existing {
+                    Some(leaf) if leaves.is_empty() => Ok(leaf),
+                    Some(leaf) => {
+                        let at = leaves.partition_point(|n| leaf_key(n).0 < key.0);
+                        leaves.insert(at, leaf);
+                        Ok(build_subtree(leaves, depth))
+                    }
+                    None => Ok(build_subtree(leaves, depth)),
+                }
+            }
+            Node::Internal {
+                index,
+                pos,
+                data,
+                left,
+                right,
+            } => {
+                assert_ne!(depth, KEY_SIZE);
+                let (lhash, rhash) = (left.hash(), right.hash());
+                let at = ops.partition_point(|(k, _)| !has_bit(k, depth));
+                let right_ops = ops.split_off(at);
+                let left = self.apply_ops(*left, ops, depth + 1)?;
+                let right = self.apply_ops(*right, right_ops, depth + 1)?;
+
+                // Nothing changed: keep the node and its storage location
+                if left.hash() == lhash && right.hash() == rhash {
+                    return Ok(Node::Internal {
+                        index,
+                        pos,
+                        data,
+                        left: left.into_boxed(),
+                        right: right.into_boxed(),
+                    });
+                }
+                Ok(join_subtrees(left, right))
+            }
+        }
     }
 
-    pub fn prove(&self, nkey: &[u8]) -> Proof {
+    pub fn prove(&self, nkey: &[u8]) -> Result<Proof> {
         let mut depth = 0;
         let hashed_key = hash(nkey);
         let mut proof = Proof::default();
-        let mut current = self.root.clone().unwrap();
+        let mut current = match self.root {
+            Some(ref root) => root.clone(),
+            // Nothing to prove against: same as an empty tree
+            None => return Ok(proof),
+        };
         loop {
             match *current {
                 Node::Empty {} => break,
-                Node::Leaf {
-                    key,
-                    value,
-                    vindex,
-                    vpos,
-                    vsize,
-                    ..
-                } => {
-                    if let Some(v) = self.store.read().unwrap().get(vindex, vpos, vsize) {
-                        if hashed_key == key {
-                            proof.proof_type = ProofType::Exists;
-                            proof.value = Some(v);
-                        } else {
-                            // We got to the leaf but the keys don't match
-                            proof.proof_type = ProofType::Collision;
-                            proof.key = Some(key);
-                            proof.hash = value.map(|v| hash(v.as_slice()));
-                        }
+                Node::Leaf { key, .. } => {
+                    let v = self.leaf_value(&current)?;
+                    if hashed_key == key {
+                        proof.proof_type = ProofType::Exists;
+                        proof.value = Some(v);
+                    } else {
+                        // We got to the leaf but the keys don't match
+                        proof.proof_type = ProofType::Collision;
+                        proof.key = Some(key);
+                        proof.hash = Some(hash(v.as_slice()));
                     }
                     break;
                 }
@@ -250,49 +747,94 @@ impl<'db> UrkelTrie<'db> {
                     depth += 1;
                 }
                 Node::Hash { .. } => {
-                    current = self.store.read().unwrap().resolve(*current);
+                    current = self.resolve(*current)?;
                 }
             }
         }
-        proof
+        Ok(proof)
+    }
+
+    /// Write the changes to the store and make the current root the last
+    /// committed one. Returns the new root hash and what was written.
+    pub fn commit(&mut self) -> Result<CommitInfo> {
+        self.commit_with(&[])
+    }
+
+    /// Commit with a small opaque blob attached to the new root, such as a
+    /// block height and hash. It's handed back with the root by
+    /// `walk_history`. Stores that can't keep it drop it.
+    ///
+    /// If the store fails, the uncommitted changes are kept, so the commit
+    /// can be tried again. The tree is only swapped for the saved nodes
+    /// once the store has taken them all.
+    pub fn commit_with(&mut self, meta: &[u8]) -> Result<CommitInfo> {
+        self.commit_to(|store, root, journal| store.commit_with(root, meta, journal))
     }
 
-    pub fn commit(&mut self) {
+    /// Save the uncommitted nodes and hand the new root to 'commit'
+    fn commit_to<F>(&mut self, commit: F) -> Result<CommitInfo>
+    where
+        F: FnOnce(&S, Box<Node>, &[JournalEntry]) -> io::Result<CommitInfo>,
+    {
+        self.check_writable()?;
+        let start = Instant::now();
+        let root = match self.root.take() {
+            Some(root) => root,
+            None => self.committed_root(),
+        };
+        let pending = mem::take(&mut self.pending);
+        // Keys that were added and removed again since the last commit
+        // didn't change anything
+        let journal: Vec<JournalEntry> = pending
+            .values()
+            .filter(|e| e.previous.is_some() || !e.removed)
+            .cloned()
+            .collect();
+
         // Commit the nodes and set a new root
-        self.root = self
-            .root
-            .take()
-            .map(|n| self.write_to_store(n))
-            .and_then(|nr| match self.store.write().unwrap().commit(nr) {
-                Ok(nn) => Some(nn),
-                _ => None,
-            })
+        let mut written = CommitInfo::default();
+        let saved = self.write_to_store(&root, &mut written).and_then(|nr| {
+            let info = commit(&self.store, nr.clone(), &journal)?;
+            Ok((nr, info))
+        });
+        let (root, info) = match saved {
+            Ok(saved) => saved,
+            Err(e) => {
+                self.root = Some(root);
+                self.pending = pending;
+                return Err(e);
+            }
+        };
+        self.root = Some(root);
+        Ok(CommitInfo {
+            nodes: written.nodes,
+            values: written.values.saturating_sub(info.deduplicated),
+            elapsed: start.elapsed(),
+            ..info
+        })
     }
 
-    fn write_to_store(&mut self, root: Box<Node>) -> Box<Node> {
+    /// Save the nodes that aren't in the store yet, counting them in 'info'
+    fn write_to_store(&self, root: &Node, info: &mut CommitInfo) -> Result<Box<Node>> {
         match *root {
+            // Saved, and so is everything below it
+            Node::Internal { index, .. } if index != 0 => Ok(root.to_hash_node().into_boxed()),
             Node::Internal {
-                left,
-                right,
-                index,
+                ref left,
+                ref right,
                 pos,
+                data,
                 ..
             } => {
-                let left_node = self.write_to_store(left);
-                let right_node = self.write_to_store(right);
-
                 let nn = Node::Internal {
-                    index,
+                    index: 0,
                     pos,
-                    left: left_node,
-                    right: right_node,
-                    data: Digest::default(),
+                    left: self.write_to_store(left, info)?,
+                    right: self.write_to_store(right, info)?,
+                    data,
                 };
-                // If it hasn't been saved, do so
-                if index == 0 {
-                    return self.store.write().unwrap().save(nn);
-                }
-                return nn.into_hash_node().into_boxed();
+                info.nodes += 1;
+                Ok(self.store.save(nn)?)
             }
             Node::Leaf {
                 index,
@@ -303,16 +845,125 @@ impl<'db> UrkelTrie<'db> {
                 // If it hasn't been saved and it has a value...
                 if index == 0 && value.is_some() {
                     let nn = Node::new_leaf_node(key, value.clone().unwrap());
-                    return self.store.write().unwrap().save(nn);
+                    info.nodes += 1;
+                    info.values += 1;
+                    Ok(self.store.save(nn)?)
+                } else {
+                    Ok(root.to_hash_node().into_boxed())
                 }
-                return root.into_hash_node().into_boxed();
             }
-            Node::Hash { .. } => {
-                return root;
-            }
-            Node::Empty {} => {
-                return root;
+            Node::Hash { .. } | Node::Empty {} => Ok(root.clone().into_boxed()),
+        }
+    }
+}
+
+/// What `add_child` did: None if 'expected' didn't match, otherwise the
+/// value the new one replaced, if any
+type Added = Option<Option<Vec<u8>>>;
+
+/// An internal node `add_child` or `remove_child` took apart on the way down
+struct Visited {
+    // The hash node it was resolved from, if any
+    unresolved: Option<Node>,
+    index: u16,
+    pos: u32,
+    data: Digest,
+}
+
+/// Put the path `add_child` or `remove_child` took apart back together as it was, from the
+/// node it stopped at and the siblings it set aside
+fn rebuild_path(
+    bottom: Node,
+    unresolved: Option<Node>,
+    siblings: Vec<Node>,
+    visited: Vec<Visited>,
+    nkey: Digest,
+) -> Box<Node> {
+    let mut node = unresolved.unwrap_or(bottom);
+    for (depth, (sibling, v)) in siblings.into_iter().zip(visited).enumerate().rev() {
+        node = match v.unresolved {
+            Some(hash_node) => hash_node,
+            None => {
+                let (left, right) = if has_bit(&nkey, depth) {
+                    (sibling, node)
+                } else {
+                    (node, sibling)
+                };
+                Node::Internal {
+                    index: v.index,
+                    pos: v.pos,
+                    data: v.data,
+                    left: left.into_boxed(),
+                    right: right.into_boxed(),
+                }
             }
         };
     }
+    node.into_boxed()
+}
+
+fn new_leaves(ops: Vec<(Digest, Op)>) -> Vec<Node> {
+    ops.into_iter()
+        .filter_map(|(key, op)| match op {
+            Op::Put(value) => Some(Node::new_leaf_node(key, value)),
+            Op::Delete => None,
+        })
+        .collect()
+}
+
+fn leaf_key(leaf: &Node) -> Digest {
+    match leaf {
+        Node::Leaf { key, .. } => *key,
+        _ => unreachable!("Only leaves are built into subtrees"),
+    }
+}
+
+/// Build a subtree from leaves sorted by key. Like `set`, a leaf sits at the
+/// first depth where no other key shares its prefix.
+fn build_subtree(mut leaves: Vec<Node>, depth: usize) -> Node {
+    match leaves.len() {
+        0 => Node::empty(),
+        1 => leaves.pop().unwrap(),
+        _ => {
+            assert_ne!(depth, KEY_SIZE);
+            let at = leaves.partition_point(|n| !has_bit(&leaf_key(n), depth));
+            let right = leaves.split_off(at);
+            let (left, right) = build_children(leaves, right, depth + 1);
+            Node::new_internal_node(left, right)
+        }
+    }
+}
+
+/// Below this depth subtrees are small enough that building them on
+/// another thread costs more than it saves
+#[cfg(feature = "parallel")]
+const PARALLEL_BUILD_DEPTH: usize = 10;
+
+/// Nodes are hashed as they're built, so with the 'parallel' feature big
+/// batches build (and hash) both sides of the top of the subtree at once
+#[cfg(feature = "parallel")]
+fn build_children(left: Vec<Node>, right: Vec<Node>, depth: usize) -> (Node, Node) {
+    if depth < PARALLEL_BUILD_DEPTH && left.len() > 1 && right.len() > 1 {
+        rayon::join(
+            || build_subtree(left, depth),
+            || build_subtree(right, depth),
+        )
+    } else {
+        (build_subtree(left, depth), build_subtree(right, depth))
+    }
+}
+
+#[cfg(not(feature = "parallel"))]
+fn build_children(left: Vec<Node>, right: Vec<Node>, depth: usize) -> (Node, Node) {
+    (build_subtree(left, depth), build_subtree(right, depth))
+}
+
+/// Put two rebuilt siblings back together. Like `remove`, a leaf left without
+/// a sibling moves up to take the parent's place.
+fn join_subtrees(left: Node, right: Node) -> Node {
+    match (left, right) {
+        (Node::Empty {}, Node::Empty {}) => Node::empty(),
+        (Node::Empty {}, n) | (n, Node::Empty {}) if n.is_leaf() => n,
+        (left, right) => Node::new_internal_node(left, right),
+    }
 }
@@ -1,22 +1,115 @@
+use super::cache::{Cache, CacheStats};
+use super::dedup::{ValueIndex, ValueLocation};
+#[cfg(feature = "encryption")]
+use super::encryption::{Cipher, Encryption, SEAL_OVERHEAD};
 use super::errors::{Error, Result};
+use super::hasher::{hash_leaf_value, Digest};
+use super::journal::{JournalEntry, JournalFile};
 use super::node::{Node, INTERNAL_NODE_SIZE, LEAF_NODE_SIZE};
-use super::TrieStore;
+use super::{CommitInfo, RootInfo, TrieStore};
+#[cfg(feature = "encryption")]
+use blake2_rfc::blake2b::blake2b;
+use blake2_rfc::blake2b::{Blake2b, Blake2bResult};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use fs2::FileExt;
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+use std::borrow::Cow;
+use std::collections::hash_map::{Entry, HashMap};
+use std::convert::TryFrom;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
 
 const META_ENTRY_SIZE: u64 = 16;
 const META_MAGIC: u32 = 0x6d726b6c;
+// Marks the entry before a meta that points to the commit's metadata
+const META_DATA_MAGIC: u32 = 0x6d647461;
+// Marks the entry before a meta with a checksum of the commit
+const META_CHECKSUM_MAGIC: u32 = 0x6d636b73;
+const META_CHECKSUM_SIZE: usize = 8;
+// How much of the log to read at a time when looking for old metas
+const META_SCAN_CHUNK: u64 = 64 * 1024;
+// Values up to this size are kept in their leaf's record, so one read gets
+// both. Larger values stay shareable by dedup even if they compress well.
+const INLINE_VALUE_SIZE: usize = 32;
+// Set in a meta's root index when the root is a leaf with its value inline
+const META_INLINE_FLAG: u16 = 0x8000;
 const WRITE_BUFFER_CAPACITY: usize = 1024 * 1024 * 4; // 4mb
+const LOCK_FILENAME: &str = "LOCK";
+// Read only stores hold a shared lock on it while they're open
+const READERS_FILENAME: &str = "READERS";
 
+/// Options used when opening a store
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Open an existing store for reading only. Nothing is created or
+    /// written, and the directory lock isn't taken.
+    pub read_only: bool,
+    /// Maximum number of decoded nodes (and values) to keep in the cache.
+    /// It's a count of entries, not bytes: with `cache_values` an entry can
+    /// be as large as a value. 0 (the default) disables the cache. It pays
+    /// off when reads keep going back to the same keys; for reads spread
+    /// over a large tree the bookkeeping costs more than it saves.
+    pub cache_size: usize,
+    /// Cache leaf values as well as nodes. Values stored inline are cached
+    /// with their leaf.
+    pub cache_values: bool,
+    /// When commits are synced to disk
+    pub sync: SyncPolicy,
+    /// Keep a journal of the keys each commit changes, in a file next to
+    /// the log. See the `journal` module.
+    pub journal: bool,
+    /// Encrypt values (and optionally nodes) in the log. See the
+    /// `encryption` module.
+    #[cfg(feature = "encryption")]
+    pub encryption: Option<Encryption>,
+    /// Store each distinct value once, however many leaves have it. The
+    /// index of values written is kept in memory and in a file next to the
+    /// log.
+    pub dedup_values: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            read_only: false,
+            cache_size: 0,
+            cache_values: false,
+            sync: SyncPolicy::Always,
+            journal: false,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            dedup_values: false,
+        }
+    }
+}
+
+/// When `commit` syncs the log to disk. Commits that aren't synced yet are
+/// lost in a crash (the store reopens at the last one that fully made it to
+/// disk), but never leave the log corrupt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync every commit
+    Always,
+    /// Sync every n commits
+    EveryN(u32),
+    /// Leave it to the OS, or to `UrkelTrie::sync`. The store is also synced
+    /// when it's closed.
+    Never,
+}
+
+#[derive(Clone)]
 struct Meta {
     pub index: u16,
     pub pos: u32,
     pub root_index: u16,
     pub root_pos: u32,
     pub is_leaf: bool,
+    pub inline: bool,
 }
 
 impl Default for Meta {
@@ -27,13 +120,14 @@ impl Default for Meta {
             root_index: 1,
             root_pos: 0,
             is_leaf: false,
+            inline: false,
         }
     }
 }
 
 impl Meta {
-    pub fn open(dir: &str, file_id: u16) -> Result<Meta> {
-        let logfilename = get_log_filename(dir, file_id);
+    pub fn open(dir: &Path, file_id: u16) -> Result<Meta> {
+        let logfilename = get_db_file_path(dir, file_id);
         let mut file = get_file(&logfilename, false)?; // read only
 
         let mut file_size: u64 = 0;
@@ -49,6 +143,7 @@ impl Meta {
                 root_index: file_id,
                 root_pos: 0,
                 is_leaf: false,
+                inline: false,
             });
         }
 
@@ -66,28 +161,39 @@ impl Meta {
             file.seek(SeekFrom::Start(start_pos as u64))?;
             file.read_exact(&mut buffer)?;
 
-            let mut rdr = Cursor::new(buffer);
-            let result = rdr.read_u32::<LittleEndian>().unwrap();
-            if result == META_MAGIC {
-                let meta_index = rdr.read_u16::<LittleEndian>()?;
-                let meta_pos = rdr.read_u32::<LittleEndian>()?;
-                let root_index = rdr.read_u16::<LittleEndian>()?;
-                let root_pos = rdr.read_u32::<LittleEndian>()?;
-
-                let adj_root_pos = root_pos >> 1;
-                let is_leaf = root_pos & 1 == 1;
-
-                return Ok(Meta {
-                    index: meta_index,
-                    pos: meta_pos,
-                    root_index,
-                    root_pos: adj_root_pos,
-                    is_leaf,
-                });
+            // A meta records its own position
+            if let Some(meta) = Meta::decode(&buffer) {
+                if i64::from(meta.pos) == start_pos {
+                    return Ok(meta);
+                }
             }
         }
     }
 
+    /// Decode a meta entry. None if the bits don't start with the magic.
+    fn decode(bits: &[u8]) -> Option<Meta> {
+        let mut rdr = Cursor::new(bits);
+        if rdr.read_u32::<LittleEndian>().ok()? != META_MAGIC {
+            return None;
+        }
+        let meta_index = rdr.read_u16::<LittleEndian>().ok()?;
+        let meta_pos = rdr.read_u32::<LittleEndian>().ok()?;
+        let flagged_root_index = rdr.read_u16::<LittleEndian>().ok()?;
+        let root_pos = rdr.read_u32::<LittleEndian>().ok()?;
+
+        let adj_root_pos = root_pos >> 1;
+        let is_leaf = root_pos & 1 == 1;
+
+        Some(Meta {
+            index: meta_index,
+            pos: meta_pos,
+            root_index: flagged_root_index & !META_INLINE_FLAG,
+            root_pos: adj_root_pos,
+            is_leaf,
+            inline: flagged_root_index & META_INLINE_FLAG != 0,
+        })
+    }
+
     pub fn encode(&self) -> io::Result<Vec<u8>> {
         // encode leaf flag
         let flagged_rpos = if self.is_leaf {
@@ -99,52 +205,362 @@ impl Meta {
         wtr.write_u32::<LittleEndian>(META_MAGIC)?;
         wtr.write_u16::<LittleEndian>(self.index)?;
         wtr.write_u32::<LittleEndian>(self.pos)?;
-        wtr.write_u16::<LittleEndian>(self.root_index)?;
+        // encode inline flag
+        let flagged_root_index = if self.inline {
+            self.root_index | META_INLINE_FLAG
+        } else {
+            self.root_index
+        };
+        wtr.write_u16::<LittleEndian>(flagged_root_index)?;
         wtr.write_u32::<LittleEndian>(flagged_rpos)?;
         Ok(wtr)
     }
 }
 
-pub struct Store<'a> {
-    dir: &'a Path,
-    logfiles: Vec<u16>,
+/// The entry written just before a meta committed with metadata: where the
+/// blob is in the same file, and the position of the meta it belongs to so
+/// it can't be confused with node or value bytes.
+fn encode_meta_data(data_pos: u32, size: u32, meta_pos: u32) -> io::Result<Vec<u8>> {
+    let mut wtr = Vec::<u8>::with_capacity(META_ENTRY_SIZE as usize);
+    wtr.write_u32::<LittleEndian>(META_DATA_MAGIC)?;
+    wtr.write_u32::<LittleEndian>(data_pos)?;
+    wtr.write_u32::<LittleEndian>(size)?;
+    wtr.write_u32::<LittleEndian>(meta_pos)?;
+    Ok(wtr)
+}
+
+/// The blob position and size, if 'bits' is the entry for the meta at
+/// 'meta_pos'
+fn decode_meta_data(bits: &[u8], meta_pos: u32) -> Option<(u32, u32)> {
+    let mut rdr = Cursor::new(bits);
+    if rdr.read_u32::<LittleEndian>().ok()? != META_DATA_MAGIC {
+        return None;
+    }
+    let data_pos = rdr.read_u32::<LittleEndian>().ok()?;
+    let size = rdr.read_u32::<LittleEndian>().ok()?;
+    if rdr.read_u32::<LittleEndian>().ok()? != meta_pos {
+        return None;
+    }
+    Some((data_pos, size))
+}
+
+/// The entry written just before the meta of a commit that isn't synced
+/// right away: a checksum of everything the commit appended before it. On
+/// open it tells whether the commit fully made it to disk.
+fn encode_meta_checksum(checksum: &[u8], meta_pos: u32) -> io::Result<Vec<u8>> {
+    let mut wtr = Vec::<u8>::with_capacity(META_ENTRY_SIZE as usize);
+    wtr.write_u32::<LittleEndian>(META_CHECKSUM_MAGIC)?;
+    wtr.extend_from_slice(&checksum[..META_CHECKSUM_SIZE]);
+    wtr.write_u32::<LittleEndian>(meta_pos)?;
+    Ok(wtr)
+}
+
+/// The checksum, if 'bits' is the checksum entry for the meta at 'meta_pos'
+fn decode_meta_checksum(bits: &[u8], meta_pos: u32) -> Option<Vec<u8>> {
+    let mut rdr = Cursor::new(bits);
+    if rdr.read_u32::<LittleEndian>().ok()? != META_CHECKSUM_MAGIC {
+        return None;
+    }
+    let mut checksum = vec![0u8; META_CHECKSUM_SIZE];
+    rdr.read_exact(&mut checksum).ok()?;
+    if rdr.read_u32::<LittleEndian>().ok()? != meta_pos {
+        return None;
+    }
+    Some(checksum)
+}
+
+/// The value compressed, if that makes it smaller
+#[cfg(feature = "lz4")]
+fn compress_value(value: &[u8]) -> Option<Vec<u8>> {
+    Some(lz4_flex::compress_prepend_size(value)).filter(|c| c.len() < value.len())
+}
+
+#[cfg(not(feature = "lz4"))]
+fn compress_value(_value: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "lz4")]
+fn decompress_value(bits: &[u8]) -> io::Result<Vec<u8>> {
+    lz4_flex::decompress_size_prepended(bits)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(not(feature = "lz4"))]
+fn decompress_value(_bits: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::other(
+        "Value is compressed: build with the 'lz4' feature to read it",
+    ))
+}
+
+/// Key for hashing values in the dedup index: one derived from the
+/// encryption key, if any
+#[cfg(feature = "encryption")]
+fn dedup_key(options: &Options) -> Vec<u8> {
+    options.encryption.as_ref().map_or_else(Vec::new, |e| {
+        blake2b(32, &e.key, b"urkel dedup").as_bytes().to_vec()
+    })
+}
+
+#[cfg(not(feature = "encryption"))]
+fn dedup_key(_options: &Options) -> Vec<u8> {
+    Vec::new()
+}
+
+/// Append only entries written before a meta
+#[derive(Default)]
+struct MetaEntries {
+    // Position and size of the commit's metadata
+    data: Option<(u32, u32)>,
+    checksum: Option<Vec<u8>>,
+}
+
+/// Append state. Only touched by 'save' and 'commit', behind a lock so
+/// readers never wait on it.
+struct Writer {
     meta: Meta,
     file: File,
     pos: u32,
     buf: Vec<u8>,
+    // Commits written since the last sync
+    unsynced: u32,
+    // Inside `UrkelTrie::group_commit`: commits aren't synced
+    group: bool,
+    // Where values already written are, if they're deduplicated
+    values: Option<ValueIndex>,
+    // Values saved since the last commit that pointed at a copy already
+    // written instead of writing another
+    deduplicated: usize,
+    // Hash of what's been flushed since the last meta, for the checksum of
+    // commits that aren't synced right away
+    flushed: Blake2b,
+}
+
+impl Writer {
+    fn write_to_buffer(&mut self, data: &[u8]) -> io::Result<u32> {
+        let write_pos = self.buf.write(data).map(|num_bits| {
+            // Record the starting position
+            let write_pos = self.pos;
+            // Increment the pos by the number of bits written
+            self.pos += num_bits as u32;
+            write_pos
+        })?;
+        // Large commits go out to the file as they're written instead of
+        // being held in memory. The file is appended to, so positions hold.
+        if self.buf.len() >= WRITE_BUFFER_CAPACITY {
+            self.flush_buffer()?;
+        }
+        Ok(write_pos)
+    }
+
+    fn flush_buffer(&mut self) -> io::Result<()> {
+        self.file.write_all(&self.buf[..])?;
+        self.flushed.update(&self.buf);
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Checksum of everything written since the last meta
+    fn checksum(&self) -> Blake2bResult {
+        let mut context = self.flushed.clone();
+        context.update(&self.buf);
+        context.finalize()
+    }
+}
+
+/// Roots found so far in the log, so each one is only read once. Filled
+/// by commits and by scans going back through the metas. Takes about 100
+/// bytes per commit.
+#[derive(Default)]
+struct RootIndex {
+    // Root hash committed by each meta, by the meta's position
+    hashes: HashMap<u32, Digest>,
+    // The newest meta for each root hash
+    metas: HashMap<[u8; 32], Meta>,
+    // Every meta from here to the end of the log is indexed. None until
+    // the first scan.
+    scanned_to: Option<u64>,
 }
 
-impl<'a> Drop for Store<'a> {
+impl RootIndex {
+    /// Index the root of a meta older than any indexed so far
+    fn insert_older(&mut self, meta: &Meta, hash: Digest) {
+        self.hashes.insert(meta.pos, hash);
+        self.metas.entry(hash.0).or_insert_with(|| meta.clone());
+    }
+
+    /// Index the root of a new commit
+    fn insert_newer(&mut self, meta: &Meta, hash: Digest) {
+        self.hashes.insert(meta.pos, hash);
+        self.metas.insert(hash.0, meta.clone());
+    }
+}
+
+pub struct Store {
+    dir: PathBuf,
+    writer: Mutex<Writer>,
+    roots: Mutex<RootIndex>,
+    read_only: bool,
+    // Holds the exclusive lock on 'dir' for a writer, or the shared lock on
+    // the READERS file for a read only store. Released on drop.
+    _lock: Option<File>,
+    // Read handles by file index. Reads are positional so readers can
+    // share a handle.
+    readers: RwLock<HashMap<u16, File>>,
+    // Read only maps of the log files by file index. Anything past the end
+    // of a map is read through 'readers'.
+    #[cfg(feature = "mmap")]
+    maps: RwLock<HashMap<u16, Mmap>>,
+    cache: Option<Cache>,
+    sync_policy: SyncPolicy,
+    // Journal of each commit's changes, if enabled. A read only store opens
+    // one if it exists.
+    journal: Option<Mutex<JournalFile>>,
+    #[cfg(feature = "encryption")]
+    cipher: Option<Cipher>,
+}
+
+impl Drop for Store {
     fn drop(&mut self) {
-        self.file.flush().unwrap();
-        self.file.sync_all().unwrap();
+        if self.read_only {
+            return;
+        }
+        let writer = self.writer.get_mut().unwrap();
+        if writer.unsynced > 0 {
+            writer.file.sync_all().unwrap();
+            if let Some(ref mut journal) = self.journal {
+                journal.get_mut().unwrap().sync().unwrap();
+            }
+        }
     }
 }
 
-impl<'a> Store<'a> {
-    pub fn open(dir: &str) -> Result<Store> {
+impl Store {
+    pub fn open<P: AsRef<Path>>(dir: P, options: &Options) -> Result<Store> {
+        let dir = dir.as_ref();
+        if options.read_only {
+            return Store::open_read_only(dir, options);
+        }
+
         maybe_create_dir(dir);
+        let lock = lock_dir(dir)?;
+        // So read only stores opened from now on can be seen by 'recover'
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(READERS_FILENAME))?;
 
         // Load the meta by searching 'dir' for the latest log file(s)
-        let (meta, loglist) = match load_log_files(dir) {
+        let meta = match load_log_files(dir) {
             Ok(list) => match Meta::open(dir, list[0]) {
-                Ok(m) => (m, list),
-                Err(r) => panic!(r),
+                // Nothing was fully committed. 'recover' drops what's there.
+                Err(Error::MetaRootNotFound) => Meta {
+                    index: list[0],
+                    root_index: list[0],
+                    ..Meta::default()
+                },
+                meta => meta?,
             },
-            Err(Error::NoLogFiles) => {
-                // New dir: return default Meta ...
-                // and push 1 on to the logfiles list for future references
-                let mut v = Vec::<u16>::new();
-                v.push(1);
-                (Meta::default(), v)
-            }
-            _ => panic!("Failed loading logfiles"),
+            // New dir: start with the default Meta
+            Err(Error::NoLogFiles) => Meta::default(),
+            Err(e) => return Err(e),
         };
 
-        let logfilename = get_log_filename(dir, meta.root_index);
+        let logfilename = get_db_file_path(dir, meta.root_index);
         let logfile_handle = get_file(&logfilename, true)?;
+        let journal = if options.journal {
+            Some(JournalFile::open(&get_journal_path(dir, meta.index), true)?)
+        } else {
+            None
+        };
+        let store = Store::new(dir, meta, logfile_handle, Some(lock), journal, options);
+        store.recover()?;
+        #[cfg(feature = "encryption")]
+        store.check_key()?;
+        if options.dedup_values {
+            store.open_value_index(options)?;
+        }
+        Ok(store)
+    }
+
+    /// Open an existing store for reading only. Nothing is created or written:
+    /// fails if the directory or the log files are missing. Doesn't take the
+    /// directory lock, so it can be shared with a writer.
+    fn open_read_only(dir: &Path, options: &Options) -> Result<Store> {
+        // Fails if 'dir' doesn't exist
+        let list = load_log_files(dir)?;
+        // Taken before reading anything, so it waits for a writer that's
+        // truncating the log
+        let lock = lock_readers(dir)?;
+        let meta = Meta::open(dir, list[0])?;
 
-        // Determine starting pos. Store.pos is used by the buffer to track
+        let logfilename = get_db_file_path(dir, meta.root_index);
+        let logfile_handle = get_file(&logfilename, false)?;
+        let journal_path = get_journal_path(dir, meta.index);
+        let journal = if journal_path.exists() {
+            Some(JournalFile::open(&journal_path, false)?)
+        } else {
+            None
+        };
+        let store = Store::new(dir, meta, logfile_handle, lock, journal, options);
+        #[cfg(feature = "encryption")]
+        store.check_key()?;
+        Ok(store)
+    }
+
+    /// Load the dedup index, once 'recover' has settled where the log ends
+    fn open_value_index(&self, options: &Options) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let path = get_values_path(&self.dir, writer.meta.index);
+        let end = u64::from(writer.pos);
+        writer.values = Some(ValueIndex::open(&path, end, &dedup_key(options))?);
+        Ok(())
+    }
+
+    /// Make sure an encrypted store is opened with the key it was written
+    /// with, by reading down to the first leaf and its value. Without this a
+    /// writer with the wrong key would only find out on a read.
+    #[cfg(feature = "encryption")]
+    fn check_key(&self) -> io::Result<()> {
+        if self.cipher.is_none() {
+            return Ok(());
+        }
+        let mut node = match self.get_root() {
+            Ok(root) => root,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => return Err(e),
+            // Nothing committed yet
+            Err(_) => return Ok(()),
+        };
+        loop {
+            node = match *node {
+                Node::Hash { .. } => self.resolve(*node)?,
+                Node::Internal { left, right, .. } => {
+                    if left.is_empty() {
+                        right
+                    } else {
+                        left
+                    }
+                }
+                Node::Leaf { .. } => return self.get(&node).map(|_| ()),
+                Node::Empty { .. } => return Ok(()),
+            };
+        }
+    }
+
+    /// Writers pass in the directory lock, readers their lock on READERS
+    /// if there's one
+    fn new(
+        dir: &Path,
+        meta: Meta,
+        file: File,
+        lock: Option<File>,
+        journal: Option<JournalFile>,
+        options: &Options,
+    ) -> Store {
+        let read_only = options.read_only;
+
+        // Determine starting pos. Writer.pos is used by the buffer to track
         // where to write in the file. So we set to the end of the file when
         // loading a log.
         let start_pos = if meta.pos == 0 {
@@ -153,165 +569,787 @@ impl<'a> Store<'a> {
             meta.pos + META_ENTRY_SIZE as u32
         };
 
-        Ok(Store {
-            dir: Path::new(dir),
-            pos: start_pos,
-            file: logfile_handle,
-            meta: meta,
-            logfiles: loglist,
-            buf: Vec::<u8>::with_capacity(WRITE_BUFFER_CAPACITY),
-        })
+        // A read only store never writes, so don't reserve the buffer
+        let capacity = if read_only { 0 } else { WRITE_BUFFER_CAPACITY };
+
+        Store {
+            dir: dir.to_path_buf(),
+            writer: Mutex::new(Writer {
+                meta,
+                file,
+                pos: start_pos,
+                buf: Vec::<u8>::with_capacity(capacity),
+                unsynced: 0,
+                group: false,
+                values: None,
+                deduplicated: 0,
+                flushed: Blake2b::new(32),
+            }),
+            roots: Mutex::new(RootIndex::default()),
+            read_only,
+            _lock: lock,
+            readers: RwLock::new(HashMap::new()),
+            #[cfg(feature = "mmap")]
+            maps: RwLock::new(HashMap::new()),
+            cache: Cache::new(options.cache_size, options.cache_values),
+            sync_policy: options.sync,
+            journal: journal.map(Mutex::new),
+            #[cfg(feature = "encryption")]
+            cipher: options.encryption.as_ref().map(Cipher::new),
+        }
     }
 
-    fn raw_read(&self, index: u16, pos: u32, size: usize) -> io::Result<Vec<u8>> {
-        let current_file = get_db_file_path(&self.dir, index);
-        let mut fs = get_file(&current_file, false)?;
-        fs.seek(SeekFrom::Start(pos as u64))?;
+    /// Drop whatever a crash left after the last commit that fully made it to
+    /// disk: a partly written commit, or unsynced commits that only partly
+    /// made it. The journal loses the records of the commits dropped.
+    fn recover(&self) -> Result<()> {
+        // Commits since the last one that was synced, newest first. Pages
+        // can reach the disk in any order, so a commit is only kept if it
+        // and every unsynced commit before it check out.
+        let mut unsynced = Vec::new();
+        let mut intact = None;
+        self.scan_metas(|meta| {
+            // Synced when it was committed, no need to check
+            if self.read_meta_entries(meta)?.checksum.is_none() {
+                intact = Some(meta.clone());
+                return Ok(false);
+            }
+            unsynced.push(meta.clone());
+            Ok(true)
+        })?;
+        for meta in unsynced.into_iter().rev() {
+            // Each checksum covers the commit from the end of the one before
+            let start = intact
+                .as_ref()
+                .map_or(0, |m: &Meta| m.pos + META_ENTRY_SIZE as u32);
+            if !self.is_intact(&meta, start)? {
+                break;
+            }
+            intact = Some(meta);
+        }
 
-        let mut packet = vec![0u8; size as usize];
-        fs.read(&mut packet[..])?;
+        let mut writer = self.writer.lock().unwrap();
+        let (meta, end) = match intact {
+            Some(meta) => {
+                let end = meta.pos + META_ENTRY_SIZE as u32;
+                (meta, end)
+            }
+            None => {
+                let index = writer.meta.index;
+                let meta = Meta {
+                    index,
+                    root_index: index,
+                    ..Meta::default()
+                };
+                (meta, 0)
+            }
+        };
+        if writer.file.metadata()?.len() > u64::from(end) {
+            // Open read only stores could have the bytes about to go mapped
+            // or be reading them, so wait until they're closed
+            let _readers = lock_readers_exclusive(&self.dir)?;
+            writer.file.set_len(u64::from(end))?;
+            writer.file.sync_all()?;
+            // Maps made while checking cover the old length
+            #[cfg(feature = "mmap")]
+            self.maps.write().unwrap().clear();
+        }
+        if let Some(ref journal) = self.journal {
+            journal.lock().unwrap().truncate(end)?;
+        }
+        writer.meta = meta;
+        writer.pos = end;
+        writer.flushed = Blake2b::new(32);
+        Ok(())
+    }
+
+    /// Does the commit of 'meta', which started at 'start', check out? Only
+    /// commits that weren't synced right away have a checksum; the rest are
+    /// on disk.
+    fn is_intact(&self, meta: &Meta, start: u32) -> io::Result<bool> {
+        let checksum = match self.read_meta_entries(meta)?.checksum {
+            Some(checksum) => checksum,
+            None => return Ok(true),
+        };
+        let end = meta.pos - META_ENTRY_SIZE as u32;
+        if end < start {
+            return Ok(false);
+        }
+        // A chunk at a time, as a commit can be large
+        let mut context = Blake2b::new(32);
+        let mut pos = start;
+        while pos < end {
+            let size = (end - pos).min(META_SCAN_CHUNK as u32);
+            context.update(&self.raw_read(meta.index, pos, size as usize)?);
+            pos += size;
+        }
+        Ok(context.finalize().as_bytes()[..META_CHECKSUM_SIZE] == checksum[..])
+    }
+
+    /// Sync committed data to disk. Only needed with a `SyncPolicy` other
+    /// than `Always`, or inside a group commit.
+    pub fn sync(&self) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        self.sync_writer(&mut writer)
+    }
+
+    fn sync_writer(&self, writer: &mut Writer) -> io::Result<()> {
+        if writer.unsynced == 0 {
+            return Ok(());
+        }
+        writer.file.sync_all()?;
+        // After the log, so the journal never has a commit the log doesn't
+        if let Some(ref journal) = self.journal {
+            journal.lock().unwrap().sync()?;
+        }
+        writer.unsynced = 0;
+        Ok(())
+    }
+
+    /// Start or end a group of commits that are synced together. Returns
+    /// whether a group was already started.
+    pub(crate) fn set_group(&self, group: bool) -> bool {
+        let mut writer = self.writer.lock().unwrap();
+        let was = writer.group;
+        writer.group = group;
+        was
+    }
+
+    /// Cache hit/miss counters. All zero if the cache is disabled.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache
+            .as_ref()
+            .map_or(CacheStats::default(), Cache::stats)
+    }
+
+    /// Run 'f' over 'size' bytes at 'pos' in the given file. With the 'mmap'
+    /// feature the bytes come straight from the mapped file, otherwise (or if
+    /// the range isn't mapped yet) they are read into a buffer.
+    fn with_bytes<T, F>(&self, index: u16, pos: u32, size: usize, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&[u8]) -> io::Result<T>,
+    {
+        #[cfg(feature = "mmap")]
+        {
+            let start = pos as usize;
+            let end = start + size;
+            self.maybe_map(index)?;
+            let maps = self.maps.read().unwrap();
+            if let Some(map) = maps.get(&index) {
+                if end <= map.len() {
+                    return f(&map[start..end]);
+                }
+            }
+        }
+        self.raw_read(index, pos, size).and_then(|bits| f(&bits))
+    }
 
+    /// Map the file if it isn't already
+    #[cfg(feature = "mmap")]
+    fn maybe_map(&self, index: u16) -> io::Result<()> {
+        if self.maps.read().unwrap().contains_key(&index) {
+            return Ok(());
+        }
+        // Safety: the directory lock keeps out other writers, but read only
+        // stores don't take it, so a map can outlive a writer's changes.
+        // Commits only append, which leaves mapped bytes alone. The one
+        // exception is `recover` truncating a torn commit when a writer
+        // opens. Read only stores hold a shared lock on READERS, and
+        // `recover` refuses to truncate while any do. Stores written before
+        // READERS existed don't have one until a writer opens them.
+        let map = self.with_reader(index, |file| unsafe { Mmap::map(file) })?;
+        self.maps.write().unwrap().entry(index).or_insert(map);
+        Ok(())
+    }
+
+    fn raw_read(&self, index: u16, pos: u32, size: usize) -> io::Result<Vec<u8>> {
+        let mut packet = vec![0u8; size];
+        self.with_reader(index, |file| {
+            read_exact_at(file, &mut packet, u64::from(pos))
+        })?;
         Ok(packet)
     }
 
-    fn read_node(&self, index: u16, pos: u32, is_leaf: bool) -> io::Result<Node> {
-        let packet_size = if is_leaf {
-            LEAF_NODE_SIZE
+    /// Run 'f' with the read handle for the given file index, opening
+    /// and pooling the handle on first use.
+    fn with_reader<T, F>(&self, index: u16, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&File) -> io::Result<T>,
+    {
+        {
+            let readers = self.readers.read().unwrap();
+            if let Some(file) = readers.get(&index) {
+                return f(file);
+            }
+        }
+
+        let mut readers = self.readers.write().unwrap();
+        let file = match readers.entry(index) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let path = get_db_file_path(&self.dir, index);
+                e.insert(get_file(&path, false)?)
+            }
+        };
+        f(file)
+    }
+
+    /// Bits as they're written at (index, pos): encrypted if the store
+    /// encrypts this kind of record
+    #[cfg(feature = "encryption")]
+    fn seal<'a>(
+        &self,
+        index: u16,
+        pos: u32,
+        bits: &'a [u8],
+        is_node: bool,
+    ) -> io::Result<Cow<'a, [u8]>> {
+        match self.cipher {
+            Some(ref c) if !is_node || c.encrypts_nodes() => {
+                c.seal(index, pos, bits).map(Cow::Owned)
+            }
+            _ => Ok(Cow::Borrowed(bits)),
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn seal<'a>(
+        &self,
+        _index: u16,
+        _pos: u32,
+        bits: &'a [u8],
+        _is_node: bool,
+    ) -> io::Result<Cow<'a, [u8]>> {
+        Ok(Cow::Borrowed(bits))
+    }
+
+    /// The reverse of `seal` for bits read from (index, pos)
+    #[cfg(feature = "encryption")]
+    fn unseal<'a>(
+        &self,
+        index: u16,
+        pos: u32,
+        bits: &'a [u8],
+        is_node: bool,
+    ) -> io::Result<Cow<'a, [u8]>> {
+        match self.cipher {
+            Some(ref c) if !is_node || c.encrypts_nodes() => {
+                c.open(index, pos, bits).map(Cow::Owned)
+            }
+            _ => Ok(Cow::Borrowed(bits)),
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn unseal<'a>(
+        &self,
+        _index: u16,
+        _pos: u32,
+        bits: &'a [u8],
+        _is_node: bool,
+    ) -> io::Result<Cow<'a, [u8]>> {
+        Ok(Cow::Borrowed(bits))
+    }
+
+    /// Bytes `seal` adds to a node record or value
+    #[cfg(feature = "encryption")]
+    fn sealed_overhead(&self, is_node: bool) -> usize {
+        match self.cipher {
+            Some(ref c) if !is_node || c.encrypts_nodes() => SEAL_OVERHEAD,
+            _ => 0,
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn sealed_overhead(&self, _is_node: bool) -> usize {
+        0
+    }
+
+    /// The value of 'leaf' from the bits stored at (index, pos)
+    fn decode_value(&self, leaf: &Node, index: u16, pos: u32, bits: &[u8]) -> io::Result<Vec<u8>> {
+        let bits = self.unseal(index, pos, bits, false)?;
+        if leaf.is_value_compressed() {
+            decompress_value(&bits)
         } else {
-            INTERNAL_NODE_SIZE
+            Ok(bits.into_owned())
+        }
+    }
+
+    /// Space for a value in a leaf record: the value as stored, then zeros
+    fn inline_value_size(&self) -> usize {
+        INLINE_VALUE_SIZE + self.sealed_overhead(false)
+    }
+
+    /// Read a leaf whose value is in its record, along with the value
+    fn read_inline_leaf(&self, index: u16, pos: u32) -> io::Result<(Node, Vec<u8>)> {
+        let record_size = LEAF_NODE_SIZE + self.inline_value_size() + self.sealed_overhead(true);
+        self.with_bytes(index, pos, record_size, |bits| {
+            let bits = self.unseal(index, pos, bits, true)?;
+            let (header, rest) = bits.split_at(LEAF_NODE_SIZE);
+            let mut leaf = Node::decode(header, true)?;
+            leaf.update_value_inline(true)?;
+            let (_, _, vsize) = leaf.get_value_storage_location();
+            let stored = rest.get(..vsize as usize).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Inline value too large at {}:{}", index, pos),
+                )
+            })?;
+            let value = self.decode_value(&leaf, index, pos, stored)?;
+            Ok((leaf, value))
+        })
+    }
+
+    /// Read a root as a hash node
+    fn read_root(&self, meta: &Meta) -> io::Result<Box<Node>> {
+        let (index, pos) = (meta.root_index, meta.root_pos);
+        let mut n = self.read_node(index, pos, meta.is_leaf, meta.inline)?;
+        n.update_storage_location(index, pos);
+        // Leaf records don't carry their hash. Internal nodes have it for
+        // their children, but a leaf at the root has to be hashed from its
+        // value.
+        if let Node::Leaf { key, .. } = n {
+            let value = self.get(&n)?;
+            n.update_data_value(hash_leaf_value(key, &value));
+        }
+        Ok(n.into_hash_node().into_boxed())
+    }
+
+    /// Read a node, with its value if it's a leaf with its value inline
+    fn read_node(&self, index: u16, pos: u32, is_leaf: bool, inline: bool) -> io::Result<Node> {
+        if let Some(node) = self.cache.as_ref().and_then(|c| c.get_node(index, pos)) {
+            return Ok(node);
+        }
+
+        let node = if inline {
+            let (mut leaf, value) = self.read_inline_leaf(index, pos)?;
+            leaf.update_value(value)?;
+            leaf
+        } else {
+            let packet_size = if is_leaf {
+                LEAF_NODE_SIZE
+            } else {
+                INTERNAL_NODE_SIZE
+            } + self.sealed_overhead(true);
+            self.with_bytes(index, pos, packet_size, |bits| {
+                Node::decode(&self.unseal(index, pos, bits, true)?, is_leaf)
+            })?
         };
+        if let Some(ref cache) = self.cache {
+            cache.put_node(index, pos, &node);
+        }
+        Ok(node)
+    }
 
-        self.raw_read(index, pos, packet_size)
-            .and_then(|bits| Node::decode(bits, is_leaf))
+    /// Call 'f' with each meta in the current log, newest first, until it
+    /// returns false
+    fn scan_metas<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnMut(&Meta) -> io::Result<bool>,
+    {
+        let last = u64::from(self.writer.lock().unwrap().meta.pos);
+        // Nothing committed yet
+        if last == 0 {
+            return Ok(());
+        }
+        self.scan_metas_before(last + META_ENTRY_SIZE, f)
     }
 
-    fn write_to_buffer(&mut self, data: &Vec<u8>) -> io::Result<u32> {
-        self.buf.write(data.as_slice()).and_then(|num_bits| {
-            // Record the starting position
-            let write_pos = self.pos;
-            // Increment the pos by the number of bits written
-            self.pos += num_bits as u32;
-            Ok(write_pos)
+    /// `scan_metas` starting from the metas before 'end'
+    fn scan_metas_before<F>(&self, mut end: u64, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&Meta) -> io::Result<bool>,
+    {
+        let index = self.writer.lock().unwrap().meta.index;
+
+        // Metas are aligned, so read back a chunk at a time and check each
+        // aligned entry. A meta records its own position, which tells it
+        // apart from node or value bytes that happen to look like one.
+        while end > 0 {
+            let start = end.saturating_sub(META_SCAN_CHUNK);
+            let size = (end - start) as usize;
+            let metas = self.with_bytes(index, start as u32, size, |bits| {
+                let entries = bits.chunks(META_ENTRY_SIZE as usize).enumerate();
+                Ok(entries
+                    .filter_map(|(i, entry)| {
+                        let pos = start + i as u64 * META_ENTRY_SIZE;
+                        Meta::decode(entry)
+                            .filter(|meta| meta.index == index && u64::from(meta.pos) == pos)
+                    })
+                    .collect::<Vec<_>>())
+            })?;
+            for meta in metas.iter().rev() {
+                if !f(meta)? {
+                    return Ok(());
+                }
+            }
+            end = start;
+        }
+        Ok(())
+    }
+
+    /// `scan_metas` with the hash of each meta's root. Hashes come from
+    /// 'roots' when they're indexed and are read and indexed otherwise.
+    /// With 'resume', the scan skips the metas already indexed.
+    fn scan_roots<F>(&self, resume: bool, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&Meta, Digest) -> io::Result<bool>,
+    {
+        let visit = |meta: &Meta| {
+            let indexed = self.roots.lock().unwrap().hashes.get(&meta.pos).copied();
+            let hash = match indexed {
+                Some(hash) => hash,
+                None => self.read_root(meta)?.hash(),
+            };
+            // Everything from here on has been seen
+            let mut roots = self.roots.lock().unwrap();
+            roots.insert_older(meta, hash);
+            let pos = u64::from(meta.pos);
+            roots.scanned_to = Some(roots.scanned_to.map_or(pos, |to| to.min(pos)));
+            drop(roots);
+            f(meta, hash)
+        };
+        let scanned_to = self.roots.lock().unwrap().scanned_to;
+        match scanned_to {
+            Some(end) if resume => self.scan_metas_before(end, visit),
+            _ => self.scan_metas(visit),
+        }
+    }
+
+    /// The entries just before 'meta': a pointer to the metadata, then a
+    /// checksum, each one optional
+    fn read_meta_entries(&self, meta: &Meta) -> io::Result<MetaEntries> {
+        let size = (meta.pos as usize).min(2 * META_ENTRY_SIZE as usize);
+        let start = meta.pos - size as u32;
+        self.with_bytes(meta.index, start, size, |bits| {
+            let mut entries = MetaEntries::default();
+            let mut slots = bits.rchunks_exact(META_ENTRY_SIZE as usize);
+            if let Some(last) = slots.next() {
+                entries.checksum = decode_meta_checksum(last, meta.pos);
+                let data = match entries.checksum {
+                    Some(_) => slots.next(),
+                    None => Some(last),
+                };
+                entries.data = data.and_then(|bits| decode_meta_data(bits, meta.pos));
+            }
+            Ok(entries)
+        })
+    }
+
+    /// The metadata committed with the root of 'meta'. Empty if there's none.
+    fn read_meta_data(&self, meta: &Meta) -> io::Result<Vec<u8>> {
+        match self.read_meta_entries(meta)?.data {
+            Some((pos, size)) => self.raw_read(meta.index, pos, size as usize),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Write the meta for 'root', syncing as the policy says. The journal
+    /// record goes in after the log is synced: a crash in between loses the
+    /// record, never the commit.
+    fn commit_root(
+        &self,
+        root: &Node,
+        data: &[u8],
+        journal: Option<&[JournalEntry]>,
+    ) -> io::Result<CommitInfo> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Store is read only",
+            ));
+        }
+        let (root_index, root_pos) = root.get_storage_location();
+        let is_leaf = root.is_leaf();
+        let inline = root.has_inline_value();
+        let mut writer = self.writer.lock().unwrap();
+        let writer = &mut *writer;
+        // End of the last commit
+        let start = if writer.meta.pos == 0 {
+            0
+        } else {
+            writer.meta.pos + META_ENTRY_SIZE as u32
+        };
+
+        // Metadata is written like a value, before the meta
+        let data_pos = if data.is_empty() {
+            None
+        } else {
+            Some(writer.write_to_buffer(data)?)
+        };
+
+        // Add the meta root
+        // Adding padding boundaries to the meta if needed
+        let pad_size = META_ENTRY_SIZE - (writer.pos as u64 % META_ENTRY_SIZE);
+        let padding = vec![0; pad_size as usize];
+        writer.write_to_buffer(&padding)?;
+
+        writer.unsynced += 1;
+        let sync = !writer.group
+            && match self.sync_policy {
+                SyncPolicy::Always => true,
+                SyncPolicy::EveryN(n) => writer.unsynced >= n,
+                SyncPolicy::Never => false,
+            };
+
+        // Point an entry before the meta at the metadata
+        let entries = u32::from(data_pos.is_some()) + u32::from(!sync);
+        let meta_pos = writer.pos + entries * META_ENTRY_SIZE as u32;
+        if let Some(pos) = data_pos {
+            let bits = encode_meta_data(pos, data.len() as u32, meta_pos)?;
+            writer.write_to_buffer(&bits)?;
+        }
+
+        if sync {
+            // The meta only goes out once everything it points to is on disk
+            writer.flush_buffer()?;
+            writer.file.sync_data()?;
+        } else {
+            // A commit that isn't synced right away might only partly make
+            // it to disk. The checksum lets 'recover' tell.
+            let checksum = writer.checksum();
+            let bits = encode_meta_checksum(checksum.as_bytes(), meta_pos)?;
+            writer.write_to_buffer(&bits)?;
+        }
+
+        // Save the meta. The buffer can go out to the file here, so this
+        // can fail like any write.
+        let meta = Meta {
+            index: root_index,
+            pos: writer.pos,
+            root_index,
+            root_pos,
+            is_leaf,
+            inline,
+        };
+        writer.write_to_buffer(&meta.encode()?)?;
+        writer.meta = meta;
+
+        // Dump the buffer to file!
+        writer.flush_buffer()?;
+        writer.flushed = Blake2b::new(32);
+        self.roots
+            .lock()
+            .unwrap()
+            .insert_newer(&writer.meta, root.hash());
+
+        // The file grew: drop the old map so it's remapped on the next read
+        #[cfg(feature = "mmap")]
+        self.maps.write().unwrap().remove(&writer.meta.index);
+
+        if sync {
+            writer.file.sync_all()?;
+        }
+        if let (Some(file), Some(entries)) = (&self.journal, journal) {
+            let mut file = file.lock().unwrap();
+            file.append(root.hash(), writer.meta.pos, entries)?;
+            if sync {
+                file.sync()?;
+            }
+        }
+        if let Some(ref mut values) = writer.values {
+            values.flush()?;
+        }
+        if sync {
+            writer.unsynced = 0;
+        }
+        Ok(CommitInfo {
+            root: root.hash(),
+            bytes: u64::from(writer.pos - start),
+            meta_index: writer.meta.index,
+            meta_pos: writer.meta.pos,
+            deduplicated: mem::take(&mut writer.deduplicated),
+            ..CommitInfo::default()
         })
     }
 }
 
-impl<'a> TrieStore for Store<'a> {
+impl TrieStore for Store {
     /// Write a node to storage. Returns the node transformed into boxed hash node
-    fn save(&mut self, mut node: Node) -> Box<Node> {
+    fn save(&self, mut node: Node) -> io::Result<Box<Node>> {
+        let mut writer = self.writer.lock().unwrap();
+        let index = writer.meta.index;
+        let mut inline = None;
         match node {
             Node::Leaf { ref value, .. } => {
-                let index = self.meta.index;
-                // Write value first
-                let val_pos = self
-                    .write_to_buffer(value.clone().unwrap().as_ref())
-                    .expect("Failed to get node position on write");
-                node.update_value_storage_location(index, val_pos);
-
-                // Now write the node
-                let nod_pos = node
-                    .encode()
-                    .and_then(|b| self.write_to_buffer(&b))
-                    .expect("Failed to get node position on write");
-                node.update_storage_location(index, nod_pos);
-                node.into_hash_node().into_boxed()
+                // Write value first, compressed when that saves space, then
+                // encrypted. The leaf hash is always of the plain value.
+                // Small values go in the leaf's record instead, so one read
+                // gets both.
+                let value = match value {
+                    Some(v) => v,
+                    None => return Err(io::Error::other("Leaf has no value!")),
+                };
+                let hash = writer.values.as_ref().map(|v| v.hash(value));
+                let existing = hash.and_then(|h| writer.values.as_ref()?.get(&h));
+                let location = match existing {
+                    Some(location) => {
+                        writer.deduplicated += 1;
+                        location
+                    }
+                    None => {
+                        let compressed = compress_value(value);
+                        let bits = compressed.as_deref().unwrap_or(value);
+                        // An inline value's location is its leaf's
+                        let pos = writer.pos;
+                        let is_inline = value.len() <= INLINE_VALUE_SIZE;
+                        let bits = self.seal(index, pos, bits, false)?;
+                        let size = u16::try_from(bits.len())
+                            .map_err(|_| io::Error::other("Value too large to store"))?;
+                        let location = ValueLocation {
+                            index,
+                            pos,
+                            size,
+                            compressed: compressed.is_some(),
+                        };
+                        if is_inline {
+                            inline = Some(bits.into_owned());
+                        } else {
+                            writer.write_to_buffer(&bits)?;
+                            // Other leaves can't point into a leaf record
+                            if let (Some(values), Some(hash)) = (writer.values.as_mut(), hash) {
+                                values.insert(hash, location)?;
+                            }
+                        }
+                        location
+                    }
+                };
+                node.update_value_storage_location(location.index, location.pos);
+                node.update_value_stored_size(location.size, location.compressed);
             }
-            Node::Internal { .. } => {
-                let pos = node
-                    .encode()
-                    .and_then(|b| self.write_to_buffer(&b))
-                    .expect("Failed to get node position on write");
-                node.update_storage_location(self.meta.index, pos);
-                node.into_hash_node().into_boxed()
-            }
-            _ => panic!("Can only 'put' leaf/internal nodes"),
+            Node::Internal { .. } => {}
+            _ => return Err(io::Error::other("Can only 'put' leaf/internal nodes")),
+        }
+
+        // Now write the node
+        let mut bits = node.encode()?;
+        if let Some(value) = inline {
+            bits.extend_from_slice(&value);
+            bits.resize(LEAF_NODE_SIZE + self.inline_value_size(), 0);
+            node.update_value_inline(true)?;
         }
+        let bits = self.seal(index, writer.pos, &bits, true)?;
+        let pos = writer.write_to_buffer(&bits)?;
+        node.update_storage_location(index, pos);
+        Ok(node.into_hash_node().into_boxed())
     }
 
     /// Get a leaf value
-    fn get(&self, vindex: u16, vpos: u32, vsize: u16) -> Option<Vec<u8>> {
-        match self.raw_read(vindex, vpos, vsize as usize) {
-            Ok(val) => Some(val),
-            _ => None,
+    fn get(&self, leaf: &Node) -> io::Result<Vec<u8>> {
+        let (vindex, vpos, vsize) = leaf.get_value_storage_location();
+        // Cached with its leaf, if at all
+        if leaf.has_inline_value() {
+            return self.read_inline_leaf(vindex, vpos).map(|(_, value)| value);
+        }
+        if let Some(value) = self.cache.as_ref().and_then(|c| c.get_value(vindex, vpos)) {
+            return Ok(value);
+        }
+        let value = self.with_bytes(vindex, vpos, vsize as usize, |bits| {
+            self.decode_value(leaf, vindex, vpos, bits)
+        })?;
+        if let Some(ref cache) = self.cache {
+            cache.put_value(vindex, vpos, &value);
         }
+        Ok(value)
     }
 
     // Consumes a hash node and returns a boxed leaf or internal node
-    fn resolve(&self, node: Node) -> Box<Node> {
+    fn resolve(&self, node: Node) -> io::Result<Box<Node>> {
         let (index, pos) = node.get_storage_location();
         let is_leaf = node.is_leaf();
-        self.read_node(index, pos, is_leaf)
-            .and_then(|mut n| {
-                n.update_data_value(node.get_data_value());
-                Ok(n.into_boxed())
-            })
-            .unwrap()
+        let inline = node.has_inline_value();
+        self.read_node(index, pos, is_leaf, inline).map(|mut n| {
+            n.update_storage_location(index, pos);
+            n.update_data_value(node.get_data_value());
+            n.into_boxed()
+        })
     }
 
-    fn commit(&mut self, root: Box<Node>) -> io::Result<(Box<Node>)> {
-        let (root_index, root_pos) = root.get_storage_location();
-        let is_leaf = root.is_leaf();
-
-        // Add the meta root
-        // Adding padding boundaries to the meta if needed
-        let pad_size = META_ENTRY_SIZE - (self.pos as u64 % META_ENTRY_SIZE);
-        let padding = vec![0; pad_size as usize];
-        let _ = self.write_to_buffer(&padding).unwrap();
-
-        // Update and save the meta
-        self.meta.index = root_index;
-        self.meta.pos = self.pos;
-        self.meta.root_index = root_index;
-        self.meta.root_pos = root_pos;
-        self.meta.is_leaf = is_leaf;
-        let _ = self
-            .meta
-            .encode()
-            .and_then(|bits| self.write_to_buffer(&bits))
-            .unwrap();
+    fn commit(&self, root: Box<Node>) -> io::Result<Box<Node>> {
+        self.commit_root(&root, &[], None)?;
+        Ok(root)
+    }
 
-        // Dump the buffer to file!
-        self.file.write_all(&self.buf[..])?;
+    fn commit_with(
+        &self,
+        root: Box<Node>,
+        meta: &[u8],
+        journal: &[JournalEntry],
+    ) -> io::Result<CommitInfo> {
+        self.commit_root(&root, meta, Some(journal))
+    }
 
-        // Flush
-        self.file.flush()?;
-        self.file.sync_all()?;
-        self.buf.clear();
-        Ok(root)
+    fn get_journal(&self, root_hash: Digest) -> io::Result<Option<Vec<JournalEntry>>> {
+        match self.journal {
+            Some(ref journal) => journal.lock().unwrap().find(root_hash),
+            None => Ok(None),
+        }
     }
 
     fn get_root(&self) -> io::Result<Box<Node>> {
-        let index = self.meta.root_index;
-        let pos = self.meta.root_pos;
-        let is_leaf = self.meta.is_leaf;
+        let meta = self.writer.lock().unwrap().meta.clone();
+        self.read_root(&meta)
+    }
 
-        self.read_node(index, pos, is_leaf)
-            .and_then(|mut n| {
-                n.update_storage_location(index, pos);
-                Ok(n)
-            })
-            .and_then(|n| Ok(n.into_hash_node().into_boxed()))
+    /// Roots committed or seen since the store was opened are looked up
+    /// in memory. Others are found by walking back through the metas that
+    /// haven't been seen yet, so older roots take longer to find the first
+    /// time. Only the current log file is searched.
+    fn find_root(&self, root_hash: Digest) -> io::Result<Option<Box<Node>>> {
+        let indexed = self.roots.lock().unwrap().metas.get(&root_hash.0).cloned();
+        let meta = match indexed {
+            Some(meta) => Some(meta),
+            None => {
+                let mut found = None;
+                self.scan_roots(true, |meta, hash| {
+                    if hash == root_hash {
+                        found = Some(meta.clone());
+                    }
+                    Ok(found.is_none())
+                })?;
+                found
+            }
+        };
+        meta.map(|meta| {
+            let mut root =
+                Node::new_hash_node(meta.root_index, meta.root_pos, root_hash, meta.is_leaf);
+            if meta.inline {
+                root.update_value_inline(true)?;
+            }
+            Ok(root.into_boxed())
+        })
+        .transpose()
+    }
+
+    fn walk_roots(&self, f: &mut dyn FnMut(RootInfo) -> bool) -> io::Result<()> {
+        self.scan_roots(false, |meta, hash| {
+            Ok(f(RootInfo {
+                hash,
+                meta: self.read_meta_data(meta)?,
+            }))
+        })
     }
 }
 
 // ------- lil helpers ---------
 
-// TODO: Consolidate these two
-fn get_log_filename(dir: &str, file_index: u16) -> PathBuf {
-    let path = Path::new(dir);
-    let file_id = format!("{:010}", file_index);
-    path.join(file_id)
-}
-
 /// Return a db path/filename
 fn get_db_file_path(path: &Path, file_id: u16) -> PathBuf {
     let file_id = format!("{:010}", file_id);
     path.join(file_id)
 }
 
-fn load_log_files(dir: &str) -> Result<Vec<u16>> {
-    let data_path = Path::new(dir);
-    let files = fs::read_dir(data_path)?;
+/// The journal kept next to a log file
+fn get_values_path(path: &Path, file_id: u16) -> PathBuf {
+    get_db_file_path(path, file_id).with_extension("values")
+}
+
+fn get_journal_path(path: &Path, file_id: u16) -> PathBuf {
+    get_db_file_path(path, file_id).with_extension("journal")
+}
+
+fn load_log_files(dir: &Path) -> Result<Vec<u16>> {
+    let files = fs::read_dir(dir)?;
     let mut data_files = Vec::<u16>::new();
 
     for entry in files {
@@ -343,11 +1381,76 @@ fn valid_log_filename(val: &str) -> u16 {
     u16::from_str(val).unwrap_or(0)
 }
 
-fn maybe_create_dir(dir: &str) {
-    let store_path = PathBuf::from(dir);
-    if !store_path.exists() {
-        fs::create_dir(PathBuf::from(dir)).expect("Attempted to create missing db dir");
+pub(crate) fn maybe_create_dir(dir: &Path) {
+    if !dir.exists() {
+        fs::create_dir(dir).expect("Attempted to create missing db dir");
+    }
+}
+
+/// Take an advisory, exclusive lock on 'dir' so only one writer can append
+/// to the logs. The lock is held as long as the returned file is open.
+pub(crate) fn lock_dir(dir: &Path) -> Result<File> {
+    let lockfile = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILENAME))?;
+    match lockfile.try_lock_exclusive() {
+        Ok(()) => Ok(lockfile),
+        Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+            Err(Error::Locked(dir.to_path_buf()))
+        }
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+/// Take a shared lock on the READERS file in 'dir', waiting while a writer
+/// holds it exclusively. None if no writer has created it.
+fn lock_readers(dir: &Path) -> Result<Option<File>> {
+    let file = match File::open(dir.join(READERS_FILENAME)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::Io(e)),
+    };
+    file.lock_shared()?;
+    Ok(Some(file))
+}
+
+/// Lock the READERS file in 'dir' exclusively, failing if any read only
+/// store has it
+fn lock_readers_exclusive(dir: &Path) -> Result<File> {
+    let file = File::open(dir.join(READERS_FILENAME))?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(file),
+        Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+            Err(Error::ReadersOpen(dir.to_path_buf()))
+        }
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+/// Positional read that doesn't move a shared file cursor
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
     }
+    Ok(())
 }
 
 /// Open/Create a file for read or append
@@ -0,0 +1,359 @@
+extern crate urkel_trie;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use urkel_trie::kvstore::{FileBackend, KvBackend, KvOp, KvStore};
+use urkel_trie::node::Node;
+use urkel_trie::proof::ProofType;
+use urkel_trie::trie::UrkelTrie;
+use urkel_trie::TrieStore;
+
+/// A store written only against the public API: keeps encoded nodes and
+/// values in a list and uses the list position as the storage position.
+#[derive(Default)]
+struct VecStore {
+    records: RwLock<Vec<Vec<u8>>>,
+    root: RwLock<Option<Node>>,
+    // Number of values read through 'get'
+    value_reads: Arc<AtomicUsize>,
+    // While set, resolve and commit fail
+    failing: Arc<AtomicBool>,
+}
+
+impl VecStore {
+    fn push(&self, bits: Vec<u8>) -> u32 {
+        let mut records = self.records.write().unwrap();
+        records.push(bits);
+        records.len() as u32 - 1
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if self.failing.load(Ordering::SeqCst) {
+            return Err(io::Error::other("Store is failing"));
+        }
+        Ok(())
+    }
+
+    fn record(&self, index: u16, pos: u32) -> io::Result<Vec<u8>> {
+        match self.records.read().unwrap().get(pos as usize) {
+            Some(bits) if index == 1 => Ok(bits.clone()),
+            _ => Err(io::Error::new(io::ErrorKind::NotFound, "No such record")),
+        }
+    }
+}
+
+impl TrieStore for VecStore {
+    fn save(&self, mut node: Node) -> io::Result<Box<Node>> {
+        if let Node::Leaf {
+            value: Some(ref v), ..
+        } = node
+        {
+            let vpos = self.push(v.clone());
+            node.update_value_storage_location(1, vpos);
+        }
+        let pos = node.encode().map(|bits| self.push(bits))?;
+        node.update_storage_location(1, pos);
+        Ok(node.into_hash_node().into_boxed())
+    }
+
+    fn get(&self, leaf: &Node) -> io::Result<Vec<u8>> {
+        let (vindex, vpos, _) = leaf.get_value_storage_location();
+        self.value_reads.fetch_add(1, Ordering::SeqCst);
+        self.record(vindex, vpos)
+    }
+
+    fn get_root(&self) -> io::Result<Box<Node>> {
+        let root = self.root.read().unwrap().clone();
+        Ok(root.unwrap_or_else(Node::empty).into_boxed())
+    }
+
+    fn resolve(&self, node: Node) -> io::Result<Box<Node>> {
+        self.check()?;
+        let (index, pos) = node.get_storage_location();
+        let mut n = Node::decode(&self.record(index, pos)?, node.is_leaf())?;
+        n.update_storage_location(index, pos);
+        n.update_data_value(node.get_data_value());
+        Ok(n.into_boxed())
+    }
+
+    fn commit(&self, root: Box<Node>) -> io::Result<Box<Node>> {
+        self.check()?;
+        *self.root.write().unwrap() = Some((*root).clone());
+        Ok(root)
+    }
+}
+
+#[test]
+fn test_custom_store() {
+    let mut tree = UrkelTrie::with_store(VecStore::default());
+    tree.set(b"name-1", "value-1").unwrap();
+    tree.set(b"name-2", "value-2").unwrap();
+    tree.set(b"name-3", "value-3").unwrap();
+    tree.commit().unwrap();
+
+    assert_eq!(tree.get(b"name-2").unwrap(), Some(Vec::from("value-2")));
+    assert_eq!(tree.get(b"name-4").unwrap(), None);
+
+    let mut proof = tree.prove(b"name-3").unwrap();
+    assert_eq!(proof.proof_type, ProofType::Exists);
+    assert_eq!(
+        proof.verify(tree.get_root_hash(), b"name-3"),
+        Ok(Vec::from("value-3"))
+    );
+}
+
+#[test]
+fn test_store_failures() {
+    let store = VecStore::default();
+    let failing = Arc::clone(&store.failing);
+    let mut tree = UrkelTrie::with_store(store);
+    tree.set(b"name-1", "value-1").unwrap();
+    tree.set(b"name-2", "value-2").unwrap();
+    tree.commit().unwrap();
+    let committed = tree.get_root_hash();
+
+    // A failed commit keeps the changes for another try
+    tree.set(b"name-3", "value-3").unwrap();
+    let uncommitted = tree.get_root_hash();
+    failing.store(true, Ordering::SeqCst);
+    assert!(tree.commit().is_err());
+    assert_eq!(tree.get_root_hash(), uncommitted);
+    failing.store(false, Ordering::SeqCst);
+    tree.commit().unwrap();
+    assert_eq!(tree.get_root_hash(), uncommitted);
+    assert_eq!(tree.get(b"name-3").unwrap(), Some(Vec::from("value-3")));
+
+    // A failed read drops them, and the tree still answers
+    tree.set(b"name-4", "value-4").unwrap();
+    failing.store(true, Ordering::SeqCst);
+    assert!(tree.set(b"name-5", "value-5").is_err());
+    failing.store(false, Ordering::SeqCst);
+    assert_eq!(tree.get_root_hash(), uncommitted);
+    assert_ne!(tree.get_root_hash(), committed);
+    assert_eq!(tree.get(b"name-4").unwrap(), None);
+    let mut proof = tree.prove(b"name-3").unwrap();
+    assert_eq!(
+        proof.verify(tree.get_root_hash(), b"name-3"),
+        Ok(Vec::from("value-3"))
+    );
+}
+
+#[test]
+fn test_collision_proof_from_store() {
+    let mut tree = UrkelTrie::with_store(VecStore::default());
+    tree.set(b"name-1", "value-1").unwrap();
+    tree.commit().unwrap();
+
+    // The only leaf is on disk, so its value hash has to come from the store
+    let proof = tree.prove(b"name-2").unwrap();
+    assert_eq!(proof.proof_type, ProofType::Collision);
+    assert!(proof.is_sane());
+}
+
+#[test]
+fn test_lookups_without_values() {
+    use urkel_trie::hasher::{hash, hash_leaf_value};
+
+    let store = VecStore::default();
+    let value_reads = Arc::clone(&store.value_reads);
+    let mut tree = UrkelTrie::with_store(store);
+    tree.set(b"name-1", "value-1").unwrap();
+    tree.set(b"name-2", "value-2").unwrap();
+    tree.commit().unwrap();
+
+    assert!(tree.contains(b"name-1").unwrap());
+    assert!(!tree.contains(b"name-3").unwrap());
+    assert_eq!(
+        tree.get_leaf_hash(b"name-2").unwrap(),
+        Some(hash_leaf_value(hash(b"name-2"), b"value-2"))
+    );
+    assert_eq!(tree.get_leaf_hash(b"name-3").unwrap(), None);
+    assert_eq!(value_reads.load(Ordering::SeqCst), 0);
+
+    tree.get(b"name-1").unwrap();
+    assert_eq!(value_reads.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_kv_store() {
+    let mut tree = UrkelTrie::with_store(KvStore::new(RwLock::new(BTreeMap::new())));
+    for i in 0..50 {
+        tree.set(format!("name-{}", i).as_bytes(), format!("value-{}", i))
+            .unwrap();
+    }
+    tree.commit_with(b"height-1").unwrap();
+    let first = tree.get_root_hash();
+    for i in 50..60 {
+        tree.set(format!("name-{}", i).as_bytes(), format!("value-{}", i))
+            .unwrap();
+    }
+    tree.remove(b"name-3").unwrap();
+    tree.commit_with(b"height-2").unwrap();
+
+    for i in 0..60 {
+        let expected = if i == 3 {
+            None
+        } else {
+            Some(format!("value-{}", i).into_bytes())
+        };
+        assert_eq!(
+            tree.get(format!("name-{}", i).as_bytes()).unwrap(),
+            expected
+        );
+    }
+
+    let mut proof = tree.prove(b"name-42").unwrap();
+    assert_eq!(
+        proof.verify(tree.get_root_hash(), b"name-42"),
+        Ok(Vec::from("value-42"))
+    );
+
+    // Earlier roots are kept in the history
+    let diff = tree.diff(first, tree.get_root_hash()).unwrap();
+    assert_eq!(diff.map(Result::unwrap).count(), 11);
+    assert_eq!(
+        tree.root_meta(tree.get_root_hash()).unwrap(),
+        Some(Vec::from("height-2"))
+    );
+    // Only the last root can be walked, but earlier ones are found by hash
+    assert_eq!(tree.root_meta(first).unwrap(), Some(Vec::from("height-1")));
+
+    // A damaged record is an error, not a panic
+    let mut key = vec![b'n'];
+    key.extend_from_slice(&tree.get_root_hash().0);
+    let record = tree.backend().get(&key).unwrap().unwrap();
+    tree.backend().put(&key, &record[1..]).unwrap();
+    let err = tree.get(b"name-42").unwrap_err();
+    assert!(format!("{}", err).contains("internal node"));
+}
+
+#[test]
+fn test_kv_commit_with_ops() {
+    use std::fs;
+
+    let root = {
+        let backend = FileBackend::open("data-kv-ops").unwrap();
+        let mut tree = UrkelTrie::with_store(KvStore::new(backend));
+        tree.set(b"name-1", "value-1").unwrap();
+        let ops = vec![KvOp::Put(b"height".to_vec(), b"1".to_vec())];
+        tree.commit_with_ops(b"", ops).unwrap();
+
+        // Keys of the trie can't be written
+        tree.set(b"name-2", "value-2").unwrap();
+        let ops = vec![KvOp::Delete(b"R".to_vec())];
+        assert!(tree.commit_with_ops(b"", ops).is_err());
+
+        // The changes of the failed commit go out with the next one
+        let ops = vec![
+            KvOp::Put(b"height".to_vec(), b"2".to_vec()),
+            KvOp::Put(b"index-2".to_vec(), b"name-2".to_vec()),
+        ];
+        tree.commit_with_ops(b"", ops).unwrap();
+        tree.get_root_hash()
+    };
+
+    let backend = FileBackend::open("data-kv-ops").unwrap();
+    assert_eq!(backend.get(b"height").unwrap(), Some(Vec::from("2")));
+    assert_eq!(backend.get(b"index-2").unwrap(), Some(Vec::from("name-2")));
+    let tree = UrkelTrie::with_store(KvStore::new(backend));
+    assert_eq!(root, tree.get_root_hash());
+    assert_eq!(tree.get(b"name-2").unwrap(), Some(Vec::from("value-2")));
+
+    fs::remove_dir_all("data-kv-ops").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_kv_compact() {
+    use std::fs;
+
+    {
+        let backend = FileBackend::open("data-kv-compact").unwrap();
+        let mut tree = UrkelTrie::with_store(KvStore::new(backend));
+        for i in 0..20 {
+            tree.set(format!("name-{}", i).as_bytes(), "value").unwrap();
+            let ops = vec![KvOp::Put(b"height".to_vec(), format!("{}", i).into_bytes())];
+            tree.commit_with_ops(b"", ops).unwrap();
+        }
+    }
+
+    let root = {
+        let backend = FileBackend::open("data-kv-compact").unwrap();
+        let mut tree = UrkelTrie::with_store(KvStore::new(backend));
+        let size = tree.backend().log_size();
+        tree.backend().compact().unwrap();
+        assert!(tree.backend().log_size() < size);
+        assert_eq!(
+            tree.backend().get(b"height").unwrap(),
+            Some(Vec::from("19"))
+        );
+
+        // Commits carry on in the compacted log
+        tree.set(b"name-20", "value").unwrap();
+        tree.commit().unwrap();
+        assert_eq!(tree.get(b"name-20").unwrap(), Some(Vec::from("value")));
+        tree.get_root_hash()
+    };
+
+    // A compaction that didn't finish is ignored
+    fs::write("data-kv-compact/kv.log.compact", b"partial").unwrap();
+    let backend = FileBackend::open("data-kv-compact").unwrap();
+    assert_eq!(backend.get(b"height").unwrap(), Some(Vec::from("19")));
+    let tree = UrkelTrie::with_store(KvStore::new(backend));
+    assert_eq!(root, tree.get_root_hash());
+    for i in 0..21 {
+        let key = format!("name-{}", i);
+        assert_eq!(tree.get(key.as_bytes()).unwrap(), Some(Vec::from("value")));
+    }
+    assert!(!std::path::Path::new("data-kv-compact/kv.log.compact").exists());
+
+    fs::remove_dir_all("data-kv-compact").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_kv_file_backend() {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    let root = {
+        let backend = FileBackend::open("data-kv").unwrap();
+        let mut tree = UrkelTrie::with_store(KvStore::new(backend));
+        tree.set(b"name-1", "value-1").unwrap();
+        tree.set(b"name-2", "value-2").unwrap();
+        tree.commit().unwrap();
+        tree.get_root_hash()
+    };
+
+    // Simulate a crash in the middle of writing a batch
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open("data-kv/kv.log")
+        .unwrap();
+    log.write_all(b"tbvk\x10\x00\x00\x00garbage").unwrap();
+    drop(log);
+
+    {
+        let backend = FileBackend::open("data-kv").unwrap();
+        let mut tree = UrkelTrie::with_store(KvStore::new(backend));
+        assert_eq!(root, tree.get_root_hash());
+        assert_eq!(tree.get(b"name-2").unwrap(), Some(Vec::from("value-2")));
+
+        tree.set(b"name-3", "value-3").unwrap();
+        tree.commit().unwrap();
+    }
+
+    {
+        let backend = FileBackend::open("data-kv").unwrap();
+        // Keys longer than their u16 length field are refused
+        let key = vec![b'k'; 0x10000];
+        assert!(backend.put(&key, b"value").is_err());
+        assert_eq!(backend.get(&key[..0xffff]).unwrap(), None);
+
+        let tree = UrkelTrie::with_store(KvStore::new(backend));
+        assert_eq!(tree.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+        assert_eq!(tree.get(b"name-3").unwrap(), Some(Vec::from("value-3")));
+    }
+
+    fs::remove_dir_all("data-kv").expect("Should have deleted test dir");
+}
@@ -1,36 +1,40 @@
 extern crate urkel_trie;
 
+use urkel_trie::hasher::hash;
+use urkel_trie::memorystore::MemoryStore;
+use urkel_trie::node::Node;
 use urkel_trie::proof::ProofType;
 use urkel_trie::trie::UrkelTrie;
+use urkel_trie::TrieStore;
 
 #[test]
 fn test_tree_commit() {
     use std::fs;
     {
-        let mut tree = UrkelTrie::new("data");
-        tree.set(b"name-1", "value-1");
-        tree.set(b"name-2", "value-2");
-        tree.set(b"name-3", "value-3");
-        tree.set(b"name-4", "value-4");
-        tree.commit();
+        let mut tree = UrkelTrie::new("data-commit");
+        tree.set(b"name-1", "value-1").unwrap();
+        tree.set(b"name-2", "value-2").unwrap();
+        tree.set(b"name-3", "value-3").unwrap();
+        tree.set(b"name-4", "value-4").unwrap();
+        tree.commit().unwrap();
 
-        assert_eq!(tree.get(b"name-1"), Some(Vec::from("value-1")));
-        assert_eq!(tree.get(b"name-3"), Some(Vec::from("value-3")));
+        assert_eq!(tree.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+        assert_eq!(tree.get(b"name-3").unwrap(), Some(Vec::from("value-3")));
 
-        tree.set(b"name-5", "value-5");
-        tree.set(b"name-6", "value-6");
-        tree.commit();
+        tree.set(b"name-5", "value-5").unwrap();
+        tree.set(b"name-6", "value-6").unwrap();
+        tree.commit().unwrap();
 
-        assert_eq!(tree.get(b"name-1"), Some(Vec::from("value-1")));
-        assert_eq!(tree.get(b"name-5"), Some(Vec::from("value-5")));
+        assert_eq!(tree.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+        assert_eq!(tree.get(b"name-5").unwrap(), Some(Vec::from("value-5")));
     }
 
     {
-        let tree = UrkelTrie::new("data");
-        assert_eq!(tree.get(b"name-1"), Some(Vec::from("value-1")));
-        assert_eq!(tree.get(b"name-5"), Some(Vec::from("value-5")));
+        let tree = UrkelTrie::new("data-commit");
+        assert_eq!(tree.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+        assert_eq!(tree.get(b"name-5").unwrap(), Some(Vec::from("value-5")));
 
-        let mut proof1 = tree.prove(b"name-1");
+        let mut proof1 = tree.prove(b"name-1").unwrap();
         assert_eq!(proof1.proof_type, ProofType::Exists);
 
         let r = proof1.verify(tree.get_root_hash(), b"name-1");
@@ -38,5 +42,1165 @@ fn test_tree_commit() {
         assert_eq!(Ok(Vec::from("value-1")), r);
     }
 
-    fs::remove_file("data/0000000001").expect("Should have deleted test file");
+    fs::remove_dir_all("data-commit").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_read_only() {
+    use std::fs;
+    use std::path::Path;
+    use urkel_trie::Error;
+
+    // Nothing on disk yet: should fail and not create the directory
+    assert!(UrkelTrie::open_read_only("data-readonly").is_err());
+    assert!(!Path::new("data-readonly").exists());
+
+    {
+        let mut tree = UrkelTrie::new("data-readonly");
+        tree.set(b"name-1", "value-1").unwrap();
+        tree.set(b"name-2", "value-2").unwrap();
+        tree.commit().unwrap();
+    }
+
+    let size = fs::metadata("data-readonly/0000000001").unwrap().len();
+    {
+        let mut tree = UrkelTrie::open_read_only("data-readonly").unwrap();
+        assert_eq!(tree.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+        assert_eq!(tree.get(b"name-2").unwrap(), Some(Vec::from("value-2")));
+
+        assert!(matches!(
+            tree.set(b"name-3", "value-3"),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(tree.remove(b"name-1"), Err(Error::ReadOnly)));
+        assert!(matches!(tree.commit(), Err(Error::ReadOnly)));
+        assert_eq!(tree.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+    }
+    // The log was left untouched
+    assert_eq!(
+        size,
+        fs::metadata("data-readonly/0000000001").unwrap().len()
+    );
+
+    fs::remove_dir_all("data-readonly").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_recover_with_readers() {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use urkel_trie::Error;
+
+    let dir = "data-recover-readers";
+    let log = "data-recover-readers/0000000001";
+    {
+        let mut tree = UrkelTrie::new(dir);
+        tree.set(b"name-1", "value-1").unwrap();
+        tree.commit().unwrap();
+    }
+    let size = fs::metadata(log).unwrap().len();
+
+    // Nothing to recover: readers don't get in the way of a writer
+    let reader = UrkelTrie::open_read_only(dir).unwrap();
+    drop(UrkelTrie::open(dir).unwrap());
+
+    // A crash left garbage that has to be truncated, which can't be done
+    // under an open reader
+    let mut file = OpenOptions::new().append(true).open(log).unwrap();
+    file.write_all(b"garbage").unwrap();
+    drop(file);
+    assert!(matches!(UrkelTrie::open(dir), Err(Error::ReadersOpen(_))));
+    assert_eq!(reader.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+    drop(reader);
+
+    let tree = UrkelTrie::open(dir).unwrap();
+    assert_eq!(fs::metadata(log).unwrap().len(), size);
+    assert_eq!(tree.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+    drop(tree);
+
+    fs::remove_dir_all(dir).expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_single_writer() {
+    use std::fs;
+    use urkel_trie::Error;
+
+    {
+        let mut tree = UrkelTrie::open("data-lock").unwrap();
+        tree.set(b"name-1", "value-1").unwrap();
+        tree.commit().unwrap();
+
+        // A second writer is turned away...
+        assert!(matches!(
+            UrkelTrie::open("data-lock"),
+            Err(Error::Locked(_))
+        ));
+
+        // ...but readers can share the directory
+        let reader = UrkelTrie::open_read_only("data-lock").unwrap();
+        assert_eq!(reader.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+    }
+
+    // The lock is released when the writer goes away
+    assert!(UrkelTrie::open("data-lock").is_ok());
+
+    fs::remove_dir_all("data-lock").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_node_cache() {
+    use std::fs;
+    use urkel_trie::Options;
+
+    {
+        let mut tree = UrkelTrie::new("data-cache");
+        for i in 0..100 {
+            tree.set(format!("name-{}", i).as_bytes(), format!("value-{}", i))
+                .unwrap();
+        }
+        tree.commit().unwrap();
+    }
+
+    {
+        let options = Options {
+            cache_size: 1024,
+            cache_values: true,
+            ..Options::default()
+        };
+        let tree = UrkelTrie::open_with_options("data-cache", options).unwrap();
+        assert_eq!(tree.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+        let first = tree.cache_stats();
+        assert!(first.misses > 0);
+
+        // Same path again: all served from the cache
+        assert_eq!(tree.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+        let second = tree.cache_stats();
+        assert_eq!(first.misses, second.misses);
+        assert!(second.hits > first.hits);
+    }
+
+    {
+        let options = Options {
+            cache_size: 0,
+            ..Options::default()
+        };
+        let tree = UrkelTrie::open_with_options("data-cache", options).unwrap();
+        assert_eq!(tree.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+        assert_eq!(tree.cache_stats(), Default::default());
+    }
+
+    fs::remove_dir_all("data-cache").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_owned_path() {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<UrkelTrie>();
+
+    let dir = PathBuf::from(format!("data-{}", "owned"));
+    let tree = Arc::new(Mutex::new(UrkelTrie::new(&dir)));
+
+    let writer = Arc::clone(&tree);
+    thread::spawn(move || {
+        let mut tree = writer.lock().unwrap();
+        tree.set(b"name-1", "value-1").unwrap();
+        tree.commit().unwrap();
+    })
+    .join()
+    .unwrap();
+
+    assert_eq!(
+        tree.lock().unwrap().get(b"name-1").unwrap(),
+        Some(Vec::from("value-1"))
+    );
+    drop(tree);
+
+    fs::remove_dir_all(&dir).expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_memory_store() {
+    let mut tree = UrkelTrie::with_store(MemoryStore::new());
+    for i in 0..50 {
+        tree.set(format!("name-{}", i).as_bytes(), format!("value-{}", i))
+            .unwrap();
+    }
+    let root = tree.get_root_hash();
+    tree.commit().unwrap();
+    assert_eq!(root, tree.get_root_hash());
+
+    // Values now come back out of the store
+    assert_eq!(tree.get(b"name-7").unwrap(), Some(Vec::from("value-7")));
+    assert_eq!(tree.get(b"name-70").unwrap(), None);
+
+    let mut proof = tree.prove(b"name-7").unwrap();
+    assert_eq!(proof.proof_type, ProofType::Exists);
+    assert_eq!(
+        proof.verify(tree.get_root_hash(), b"name-7"),
+        Ok(Vec::from("value-7"))
+    );
+
+    tree.remove(b"name-7").unwrap();
+    tree.set(b"name-8", "value-88").unwrap();
+    tree.commit().unwrap();
+    assert_eq!(tree.get(b"name-7").unwrap(), None);
+    assert_eq!(tree.get(b"name-8").unwrap(), Some(Vec::from("value-88")));
+    assert_ne!(root, tree.get_root_hash());
+
+    // A value too large for the leaf's size field is refused, not cut short
+    tree.set(b"large", vec![7u8; 70000]).unwrap();
+    assert!(tree.commit().is_err());
+    tree.remove(b"large").unwrap();
+    tree.commit().unwrap();
+    assert_eq!(tree.get(b"large").unwrap(), None);
+}
+
+#[test]
+fn test_tree_resolved_leaves_keep_location() {
+    let mut tree = UrkelTrie::with_store(MemoryStore::new());
+    for i in 0..20 {
+        tree.set(format!("name-{}", i).as_bytes(), "value").unwrap();
+    }
+    tree.commit().unwrap();
+
+    // New keys push committed leaves further down the tree
+    for i in 20..40 {
+        tree.set(format!("name-{}", i).as_bytes(), "value").unwrap();
+    }
+    tree.commit().unwrap();
+    for i in 0..40 {
+        let key = format!("name-{}", i);
+        assert_eq!(tree.get(key.as_bytes()).unwrap(), Some(Vec::from("value")));
+    }
+
+    // Resolving a node keeps the location it was read from
+    let store = MemoryStore::new();
+    let leaf = store
+        .save(Node::new_leaf_node(hash(b"name-1"), "value"))
+        .unwrap();
+    let location = leaf.get_storage_location();
+    let resolved = store.resolve(*leaf).unwrap();
+    assert!(resolved.is_leaf());
+    assert_eq!(location, resolved.get_storage_location());
+}
+
+#[test]
+fn test_tree_set_same_value() {
+    let mut tree = UrkelTrie::with_store(MemoryStore::new());
+    for i in 0..20 {
+        tree.set(format!("name-{}", i).as_bytes(), "value").unwrap();
+    }
+
+    // Before and after a commit, setting a key to its current value
+    // leaves the tree alone
+    for _ in 0..2 {
+        let root = tree.get_root_hash();
+        tree.set(b"name-0", "value").unwrap();
+        assert_eq!(root, tree.get_root_hash());
+        for i in 0..20 {
+            let key = format!("name-{}", i);
+            assert_eq!(tree.get(key.as_bytes()).unwrap(), Some(Vec::from("value")));
+        }
+        tree.commit().unwrap();
+    }
+}
+
+#[test]
+fn test_tree_single_key_proof() {
+    // With the only leaf at the root, the proof has no sibling hashes
+    let mut tree = UrkelTrie::with_store(MemoryStore::new());
+    tree.set(b"name-1", "value-1").unwrap();
+
+    let mut proof = tree.prove(b"name-1").unwrap();
+    assert_eq!(proof.depth(), 0);
+    assert_eq!(
+        proof.verify(tree.get_root_hash(), b"name-1"),
+        Ok(Vec::from("value-1"))
+    );
+
+    let mut proof = tree.prove(b"name-2").unwrap();
+    assert_eq!(proof.proof_type, ProofType::Collision);
+    assert!(proof.verify(tree.get_root_hash(), b"name-2").is_err());
+}
+
+#[test]
+fn test_tree_leaf_root_reopen() {
+    use std::fs;
+
+    let dir = "data-leaf-root";
+    let root = {
+        let mut tree = UrkelTrie::open(dir).unwrap();
+        tree.set(b"name-1", "value-1").unwrap();
+        tree.commit().unwrap();
+        tree.get_root_hash()
+    };
+
+    // A leaf at the root is hashed from its value when it's read back
+    {
+        let tree = UrkelTrie::open(dir).unwrap();
+        assert_eq!(root, tree.get_root_hash());
+        let mut proof = tree.prove(b"name-1").unwrap();
+        assert_eq!(proof.verify(root, b"name-1"), Ok(Vec::from("value-1")));
+    }
+
+    fs::remove_dir_all(dir).expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_shared_trie_readers() {
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use urkel_trie::shared::SharedTrie;
+
+    let shared = Arc::new(SharedTrie::new(UrkelTrie::new("data-shared")));
+    shared.set(b"name-0", "value-0").unwrap();
+    // Not visible to readers until committed
+    assert_eq!(shared.get(b"name-0").unwrap(), None);
+    shared.commit().unwrap();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let shared = Arc::clone(&shared);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    let reader = shared.reader();
+                    let root = reader.get_root_hash();
+                    assert_eq!(reader.get(b"name-0").unwrap(), Some(Vec::from("value-0")));
+                    let mut proof = reader.prove(b"name-0").unwrap();
+                    assert_eq!(proof.verify(root, b"name-0"), Ok(Vec::from("value-0")));
+                }
+            })
+        })
+        .collect();
+
+    for i in 1..50 {
+        shared
+            .set(format!("name-{}", i).as_bytes(), format!("value-{}", i))
+            .unwrap();
+        shared.commit().unwrap();
+    }
+    done.store(true, Ordering::SeqCst);
+    for r in readers {
+        r.join().unwrap();
+    }
+
+    // Readers are read only
+    assert!(shared.reader().set(b"name-1", "other").is_err());
+    assert_eq!(shared.get(b"name-49").unwrap(), Some(Vec::from("value-49")));
+
+    drop(shared);
+    fs::remove_dir_all("data-shared").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_apply_batch() {
+    use urkel_trie::trie::Op;
+
+    let mut batched = UrkelTrie::with_store(MemoryStore::new());
+    let mut sequential = UrkelTrie::with_store(MemoryStore::new());
+
+    // An empty tree, then changes on top of committed nodes
+    let first: Vec<_> = (0..200)
+        .map(|i| {
+            (
+                format!("name-{}", i),
+                Op::Put(format!("value-{}", i).into()),
+            )
+        })
+        .collect();
+    let mut second = Vec::new();
+    for i in (0..300).step_by(3) {
+        second.push((format!("name-{}", i), Op::Put(Vec::from("updated"))));
+    }
+    for i in (0..300).step_by(5) {
+        second.push((format!("name-{}", i), Op::Delete));
+    }
+    // Same value, and a delete then put of the same key
+    second.push((String::from("name-1"), Op::Put(Vec::from("value-1"))));
+    second.push((String::from("name-0"), Op::Put(Vec::from("back"))));
+
+    for ops in [first, second] {
+        for (key, op) in ops.iter() {
+            match op {
+                Op::Put(value) => sequential.set(key.as_bytes(), value.clone()).unwrap(),
+                Op::Delete => sequential.remove(key.as_bytes()).unwrap(),
+            };
+        }
+        batched.apply_batch(ops).unwrap();
+        assert_eq!(sequential.get_root_hash(), batched.get_root_hash());
+
+        batched.commit().unwrap();
+        sequential.commit().unwrap();
+        assert_eq!(sequential.get_root_hash(), batched.get_root_hash());
+    }
+
+    // Removing a missing key leaves the tree alone
+    let root = sequential.get_root_hash();
+    sequential.remove(b"name-5").unwrap();
+    assert_eq!(root, sequential.get_root_hash());
+
+    assert_eq!(batched.get(b"name-0").unwrap(), Some(Vec::from("back")));
+    assert_eq!(batched.get(b"name-3").unwrap(), Some(Vec::from("updated")));
+    assert_eq!(batched.get(b"name-5").unwrap(), None);
+    assert_eq!(batched.get(b"name-299").unwrap(), None);
+    assert_eq!(batched.get(b"name-298").unwrap(), None);
+    assert_eq!(
+        batched.get(b"name-297").unwrap(),
+        Some(Vec::from("updated"))
+    );
+
+    // Deleting everything leaves an empty tree
+    let all: Vec<_> = (0..300)
+        .map(|i| (format!("name-{}", i), Op::Delete))
+        .collect();
+    batched.apply_batch(all).unwrap();
+    assert_eq!(
+        batched.get_root_hash(),
+        UrkelTrie::with_store(MemoryStore::new()).get_root_hash()
+    );
+}
+
+#[test]
+fn test_tree_previous_values() {
+    let mut tree = UrkelTrie::with_store(MemoryStore::new());
+    assert_eq!(tree.set(b"name-1", "value-1").unwrap(), None);
+    assert_eq!(
+        tree.set(b"name-1", "value-2").unwrap(),
+        Some(Vec::from("value-1"))
+    );
+    tree.set(b"name-2", "value-2").unwrap();
+    tree.commit().unwrap();
+
+    // Previous values come out of the store once committed
+    assert_eq!(
+        tree.set(b"name-2", "value-3").unwrap(),
+        Some(Vec::from("value-2"))
+    );
+    assert_eq!(tree.remove(b"name-1").unwrap(), Some(Vec::from("value-2")));
+    assert_eq!(tree.remove(b"name-1").unwrap(), None);
+
+    // Compare and swap
+    let root = tree.get_root_hash();
+    assert!(!tree.cas(b"name-2", Some(b"value-2"), "value-4").unwrap());
+    assert!(!tree.insert_if_absent(b"name-2", "value-4").unwrap());
+    assert_eq!(root, tree.get_root_hash());
+
+    assert!(tree.cas(b"name-2", Some(b"value-3"), "value-4").unwrap());
+    assert!(tree.insert_if_absent(b"name-1", "value-1").unwrap());
+    assert_eq!(tree.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+    assert_eq!(tree.get(b"name-2").unwrap(), Some(Vec::from("value-4")));
+
+    // A swap that doesn't match puts back the committed nodes it went
+    // through, so there's nothing new to write
+    for i in 0..50 {
+        tree.set(format!("key-{}", i).as_bytes(), "value").unwrap();
+    }
+    tree.commit().unwrap();
+    let root = tree.get_root_hash();
+    assert!(!tree.cas(b"key-7", Some(b"other"), "new").unwrap());
+    assert!(!tree.cas(b"key-70", Some(b"value"), "new").unwrap());
+    assert!(!tree.insert_if_absent(b"key-8", "new").unwrap());
+    assert_eq!(root, tree.get_root_hash());
+    assert_eq!(tree.commit().unwrap().nodes, 0);
+    assert_eq!(tree.get(b"key-7").unwrap(), Some(Vec::from("value")));
+}
+
+#[test]
+fn test_tree_diff() {
+    use std::fs;
+    use urkel_trie::diff::Change;
+    use urkel_trie::hasher::{hash, Digest};
+    use urkel_trie::Error;
+
+    let key = |i: u32| format!("name-{}", i);
+    let sorted = |mut changes: Vec<Change>| {
+        changes.sort_by_key(|c| match c {
+            Change::Added(k, _) | Change::Removed(k) | Change::Changed(k, _, _) => k.0,
+        });
+        changes
+    };
+
+    let (first, second) = {
+        let mut tree = UrkelTrie::new("data-diff");
+        for i in 0..100 {
+            tree.set(key(i).as_bytes(), "value").unwrap();
+        }
+        tree.commit().unwrap();
+        let first = tree.get_root_hash();
+
+        tree.set(key(100).as_bytes(), "value").unwrap();
+        tree.set(key(7).as_bytes(), "changed").unwrap();
+        tree.remove(key(42).as_bytes()).unwrap();
+        tree.commit().unwrap();
+        (first, tree.get_root_hash())
+    };
+
+    // Reopened: the first root is only found by going back through the log
+    let tree = UrkelTrie::new("data-diff");
+    let changes: Vec<Change> = tree
+        .diff(first, second)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        changes,
+        sorted(vec![
+            Change::Added(hash(key(100).as_bytes()), Vec::from("value")),
+            Change::Changed(
+                hash(key(7).as_bytes()),
+                Vec::from("value"),
+                Vec::from("changed")
+            ),
+            Change::Removed(hash(key(42).as_bytes())),
+        ])
+    );
+
+    assert_eq!(tree.diff(second, second).unwrap().count(), 0);
+    assert_eq!(tree.diff(Digest::zero(), first).unwrap().count(), 100);
+    assert!(matches!(
+        tree.diff(first, hash(b"nope")),
+        Err(Error::UnknownRoot(_))
+    ));
+
+    // Roots already found are looked up again without a scan, and the
+    // history still has all of them
+    assert_eq!(tree.diff(first, second).unwrap().count(), 3);
+    let mut roots = Vec::new();
+    tree.walk_history(|info| {
+        roots.push(info.hash);
+        true
+    })
+    .unwrap();
+    assert_eq!(roots, vec![second, first]);
+    drop(tree);
+
+    fs::remove_dir_all("data-diff").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_journal() {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use urkel_trie::hasher::hash;
+    use urkel_trie::trie::Op;
+    use urkel_trie::{Error, Options};
+
+    let key = |i: u32| format!("name-{}", i);
+    let options = Options {
+        journal: true,
+        ..Options::default()
+    };
+
+    let (first, second) = {
+        let mut tree = UrkelTrie::open_with_options("data-journal", options.clone()).unwrap();
+        for i in 0..50 {
+            tree.set(key(i).as_bytes(), "value").unwrap();
+        }
+        tree.commit().unwrap();
+        let first = tree.get_root_hash();
+
+        tree.set(key(50).as_bytes(), "value").unwrap();
+        tree.set(key(7).as_bytes(), "changed").unwrap();
+        tree.set(key(7).as_bytes(), "changed again").unwrap();
+        tree.remove(key(42).as_bytes()).unwrap();
+        // Added and removed again: not in the journal
+        tree.set(key(60).as_bytes(), "value").unwrap();
+        tree.remove(key(60).as_bytes()).unwrap();
+        // Same value: not a change
+        tree.set(key(8).as_bytes(), "value").unwrap();
+        tree.apply_batch(vec![
+            (key(9), Op::Delete),
+            (key(51), Op::Put(b"v".to_vec())),
+        ])
+        .unwrap();
+        tree.commit().unwrap();
+        (first, tree.get_root_hash())
+    };
+
+    // A torn record at the end is dropped on open
+    OpenOptions::new()
+        .append(true)
+        .open("data-journal/0000000001.journal")
+        .unwrap()
+        .write_all(b"torn")
+        .unwrap();
+
+    let mut tree = UrkelTrie::open_with_options("data-journal", options).unwrap();
+    let journal = tree.journal(second).unwrap().unwrap();
+    let mut keys: Vec<_> = [7, 9, 42, 50, 51]
+        .iter()
+        .map(|i| hash(key(*i).as_bytes()))
+        .collect();
+    keys.sort_by_key(|k| k.0);
+    assert_eq!(journal.iter().map(|e| e.key).collect::<Vec<_>>(), keys);
+    for entry in &journal {
+        let new_key =
+            entry.key == hash(key(50).as_bytes()) || entry.key == hash(key(51).as_bytes());
+        assert_eq!(entry.previous.is_none(), new_key);
+        let removed = entry.key == hash(key(9).as_bytes()) || entry.key == hash(key(42).as_bytes());
+        assert_eq!(entry.removed, removed);
+    }
+    assert_eq!(tree.journal(first).unwrap().unwrap().len(), 50);
+
+    // Undo the second commit
+    tree.revert(second).unwrap();
+    assert_eq!(tree.get_root_hash(), first);
+    assert_eq!(
+        tree.get(key(7).as_bytes()).unwrap(),
+        Some(Vec::from("value"))
+    );
+    tree.commit().unwrap();
+    assert_eq!(tree.journal(first).unwrap().unwrap().len(), 5);
+
+    // The same root committed again: its latest journal is used, so this
+    // undoes the revert
+    tree.revert(first).unwrap();
+    assert_eq!(tree.get_root_hash(), second);
+    assert!(matches!(
+        tree.revert(hash(b"nope")),
+        Err(Error::NoJournal(_))
+    ));
+    drop(tree);
+
+    fs::remove_dir_all("data-journal").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_commit_meta() {
+    use std::fs;
+
+    let roots = {
+        let mut tree = UrkelTrie::new("data-meta");
+        let mut roots = Vec::new();
+        for height in 0u32..20 {
+            tree.set(format!("name-{}", height).as_bytes(), "value")
+                .unwrap();
+            if height == 10 {
+                tree.commit().unwrap();
+            } else {
+                tree.commit_with(&height.to_le_bytes()).unwrap();
+            }
+            roots.push(tree.get_root_hash());
+        }
+        roots
+    };
+
+    let tree = UrkelTrie::open_read_only("data-meta").unwrap();
+    let mut history = Vec::new();
+    tree.walk_history(|info| {
+        history.push(info.clone());
+        true
+    })
+    .unwrap();
+    history.reverse();
+    assert_eq!(history.len(), 20);
+    for (height, info) in history.iter().enumerate() {
+        assert_eq!(info.hash, roots[height]);
+        if height == 10 {
+            assert!(info.meta.is_empty());
+        } else {
+            assert_eq!(info.meta, (height as u32).to_le_bytes());
+        }
+    }
+
+    // Stop early
+    let mut seen = 0;
+    tree.walk_history(|_| {
+        seen += 1;
+        seen < 3
+    })
+    .unwrap();
+    assert_eq!(seen, 3);
+
+    assert_eq!(
+        tree.root_meta(roots[4]).unwrap(),
+        Some(4u32.to_le_bytes().to_vec())
+    );
+    assert_eq!(
+        tree.root_meta(urkel_trie::hasher::hash(b"nope")).unwrap(),
+        None
+    );
+    drop(tree);
+
+    // The memory store keeps it too
+    let mut tree = UrkelTrie::with_store(MemoryStore::new());
+    tree.set(b"name-1", "value-1").unwrap();
+    tree.commit_with(b"height-1").unwrap();
+    assert_eq!(
+        tree.root_meta(tree.get_root_hash()).unwrap(),
+        Some(Vec::from("height-1"))
+    );
+
+    fs::remove_dir_all("data-meta").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_commit_info() {
+    use std::fs;
+
+    let mut tree = UrkelTrie::new("data-info");
+    for i in 0..4 {
+        tree.set(format!("name-{}", i).as_bytes(), "value").unwrap();
+    }
+    let info = tree.commit().unwrap();
+    assert_eq!(info.root, tree.get_root_hash());
+    // 4 leaves and at least 3 internal nodes to join them
+    assert_eq!(info.values, 4);
+    assert!(info.nodes >= 7);
+    let size = fs::metadata("data-info/0000000001").unwrap().len();
+    assert_eq!(info.bytes, size);
+    assert_eq!((info.meta_index, u64::from(info.meta_pos) + 16), (1, size));
+
+    // Nothing new: just the padding and the meta
+    let info = tree.commit().unwrap();
+    assert_eq!((info.nodes, info.values), (0, 0));
+    assert_eq!(info.bytes, 32);
+    assert_eq!(info.root, tree.get_root_hash());
+
+    // Removing a missing key puts the committed path back as it was
+    tree.remove(b"missing").unwrap();
+    let info = tree.commit().unwrap();
+    assert_eq!((info.nodes, info.values), (0, 0));
+
+    // So does setting a key to its current value
+    tree.set(b"name-0", "value").unwrap();
+    let info = tree.commit().unwrap();
+    assert_eq!((info.nodes, info.values), (0, 0));
+    drop(tree);
+
+    fs::remove_dir_all("data-info").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_sync_policy() {
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::panic::{self, AssertUnwindSafe};
+    use urkel_trie::{Options, SyncPolicy};
+
+    let options = Options {
+        sync: SyncPolicy::Never,
+        ..Options::default()
+    };
+    let log = "data-sync/0000000001";
+
+    let (roots, last) = {
+        let mut tree = UrkelTrie::open_with_options("data-sync", options.clone()).unwrap();
+        let mut roots = Vec::new();
+        let mut last = None;
+        for i in 0..5 {
+            tree.set(format!("name-{}", i).as_bytes(), "value").unwrap();
+            last = Some(tree.commit().unwrap());
+            roots.push(tree.get_root_hash());
+        }
+        (roots, last.unwrap())
+    };
+
+    // Closing syncs
+    let tree = UrkelTrie::open_with_options("data-sync", options.clone()).unwrap();
+    assert_eq!(tree.get_root_hash(), roots[4]);
+    drop(tree);
+
+    // The last commit only partly made it to disk: reopen at the one before
+    let start = u64::from(last.meta_pos) + 16 - last.bytes;
+    let mut file = OpenOptions::new().write(true).open(log).unwrap();
+    file.seek(SeekFrom::Start(start)).unwrap();
+    file.write_all(&[0xff; 8]).unwrap();
+    drop(file);
+
+    let mut tree = UrkelTrie::open_with_options("data-sync", options).unwrap();
+    assert_eq!(tree.get_root_hash(), roots[3]);
+    assert_eq!(fs::metadata(log).unwrap().len(), start);
+    assert_eq!(tree.get(b"name-4").unwrap(), None);
+
+    // A group of commits is synced once
+    let hash = tree
+        .group_commit(|tree| {
+            for i in 4..8 {
+                tree.set(format!("name-{}", i).as_bytes(), "value").unwrap();
+                tree.commit()?;
+            }
+            Ok(tree.get_root_hash())
+        })
+        .unwrap();
+    drop(tree);
+
+    // Garbage after the last commit is dropped
+    let size = fs::metadata(log).unwrap().len();
+    let mut file = OpenOptions::new().append(true).open(log).unwrap();
+    file.write_all(b"garbage").unwrap();
+    drop(file);
+
+    let mut tree = UrkelTrie::new("data-sync");
+    assert_eq!(tree.get_root_hash(), hash);
+    assert_eq!(fs::metadata(log).unwrap().len(), size);
+    tree.set(b"name-8", "value").unwrap();
+    tree.commit().unwrap();
+    drop(tree);
+
+    let mut tree = UrkelTrie::new("data-sync");
+    assert_eq!(tree.get(b"name-8").unwrap(), Some(Vec::from("value")));
+    assert_eq!(tree.get(b"name-7").unwrap(), Some(Vec::from("value")));
+
+    // A group that panics still ends: the next commit is synced right
+    // away, so it has no checksum entry before its meta
+    let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+        tree.group_commit(|_| -> urkel_trie::Result<()> { panic!("in a group") })
+    }));
+    assert!(panicked.is_err());
+    tree.set(b"name-9", "value").unwrap();
+    let info = tree.commit().unwrap();
+    let bits = fs::read(log).unwrap();
+    let entry = info.meta_pos as usize - 16;
+    assert_ne!(&bits[entry..entry + 4], b"skcm");
+    drop(tree);
+
+    fs::remove_dir_all("data-sync").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_torn_earlier_commit() {
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use urkel_trie::{Options, SyncPolicy};
+
+    let options = Options {
+        sync: SyncPolicy::Never,
+        journal: true,
+        ..Options::default()
+    };
+    let log = "data-torn/0000000001";
+
+    let infos: Vec<_> = {
+        let mut tree = UrkelTrie::open_with_options("data-torn", options.clone()).unwrap();
+        (0..3)
+            .map(|i| {
+                tree.set(format!("name-{}", i).as_bytes(), "value").unwrap();
+                tree.commit().unwrap()
+            })
+            .collect()
+    };
+
+    // The second commit didn't make it to disk but the third did: both go
+    let start = u64::from(infos[1].meta_pos) + 16 - infos[1].bytes;
+    let mut file = OpenOptions::new().write(true).open(log).unwrap();
+    file.seek(SeekFrom::Start(start)).unwrap();
+    file.write_all(&[0; 64]).unwrap();
+    drop(file);
+
+    let mut tree = UrkelTrie::open_with_options("data-torn", options).unwrap();
+    assert_eq!(tree.get_root_hash(), infos[0].root);
+    assert_eq!(fs::metadata(log).unwrap().len(), start);
+    assert_eq!(tree.get(b"name-1").unwrap(), None);
+    // And so do their journal records
+    assert!(tree.journal(infos[0].root).unwrap().is_some());
+    assert!(tree.journal(infos[1].root).unwrap().is_none());
+    assert!(tree.journal(infos[2].root).unwrap().is_none());
+
+    tree.set(b"name-1", "other").unwrap();
+    let info = tree.commit().unwrap();
+    assert_eq!(tree.journal(info.root).unwrap().unwrap().len(), 1);
+    drop(tree);
+
+    fs::remove_dir_all("data-torn").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_large_commit() {
+    use std::fs;
+    use urkel_trie::{Options, SyncPolicy};
+
+    // Well past the write buffer, so the commit is streamed to the file
+    let value = |i: u32| vec![i as u8; 4096];
+    let never = Options {
+        sync: SyncPolicy::Never,
+        ..Options::default()
+    };
+
+    for (round, options) in [never, Options::default()].iter().enumerate() {
+        let root = {
+            let mut tree = UrkelTrie::open_with_options("data-large", options.clone()).unwrap();
+            for i in 0..1200 {
+                let key = format!("name-{}-{}", round, i);
+                tree.set(key.as_bytes(), value(i)).unwrap();
+            }
+            let info = tree.commit().unwrap();
+            let size = fs::metadata("data-large/0000000001").unwrap().len();
+            assert_eq!(u64::from(info.meta_pos) + 16, size);
+            info.root
+        };
+
+        // Reopening checks the commit made it to disk in full
+        let tree = UrkelTrie::open_with_options("data-large", options.clone()).unwrap();
+        assert_eq!(tree.get_root_hash(), root);
+        for i in (0..1200).step_by(97) {
+            let key = format!("name-{}-{}", round, i);
+            assert_eq!(tree.get(key.as_bytes()).unwrap(), Some(value(i)));
+        }
+    }
+
+    fs::remove_dir_all("data-large").expect("Should have deleted test dir");
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn test_tree_compressed_values() {
+    use std::fs;
+
+    let repetitive = vec![b'a'; 2000];
+    // Short and without repeats: compressing would only make it larger
+    let raw = b"not worth it".to_vec();
+    let root = {
+        let mut tree = UrkelTrie::new("data-lz4");
+        tree.set(b"repetitive", repetitive.clone()).unwrap();
+        tree.set(b"raw", raw.clone()).unwrap();
+        tree.commit().unwrap();
+        // A couple of compressed leaves and the internal nodes are far
+        // smaller than the value itself
+        let size = fs::metadata("data-lz4/0000000001").unwrap().len();
+        assert!(size < 1000);
+        assert_eq!(tree.get(b"repetitive").unwrap(), Some(repetitive.clone()));
+        tree.get_root_hash()
+    };
+
+    let tree = UrkelTrie::open("data-lz4").unwrap();
+    assert_eq!(tree.get_root_hash(), root);
+    assert_eq!(tree.get(b"repetitive").unwrap(), Some(repetitive.clone()));
+    assert_eq!(tree.get(b"raw").unwrap(), Some(raw));
+
+    // Proofs carry and hash the uncompressed value
+    let mut proof = tree.prove(b"repetitive").unwrap();
+    assert_eq!(proof.proof_type, ProofType::Exists);
+    assert_eq!(proof.verify(root, b"repetitive"), Ok(repetitive));
+    drop(tree);
+
+    fs::remove_dir_all("data-lz4").expect("Should have deleted test dir");
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_tree_encryption() {
+    use std::fs;
+    use urkel_trie::{Encryption, Options};
+
+    let options = |key, nodes| Options {
+        encryption: Some(Encryption { key, nodes }),
+        ..Options::default()
+    };
+
+    let mut plain = UrkelTrie::with_store(MemoryStore::new());
+    for i in 0..20 {
+        plain
+            .set(format!("name-{}", i).as_bytes(), format!("secret-{}", i))
+            .unwrap();
+    }
+    plain.commit().unwrap();
+
+    for &nodes in &[false, true] {
+        let root = {
+            let mut tree =
+                UrkelTrie::open_with_options("data-crypt", options([7; 32], nodes)).unwrap();
+            for i in 0..20 {
+                tree.set(format!("name-{}", i).as_bytes(), format!("secret-{}", i))
+                    .unwrap();
+            }
+            tree.commit().unwrap();
+            tree.get_root_hash()
+        };
+        // Same trie as in plaintext, and nothing readable on disk
+        assert_eq!(root, plain.get_root_hash());
+        let log = fs::read("data-crypt/0000000001").unwrap();
+        assert!(!log.windows(6).any(|w| w == b"secret"));
+
+        // Nonces are random, so the same records under the same key come
+        // out different
+        {
+            let mut tree =
+                UrkelTrie::open_with_options("data-crypt-2", options([7; 32], nodes)).unwrap();
+            for i in 0..20 {
+                tree.set(format!("name-{}", i).as_bytes(), format!("secret-{}", i))
+                    .unwrap();
+            }
+            tree.commit().unwrap();
+            assert_eq!(tree.get_root_hash(), root);
+        }
+        let other = fs::read("data-crypt-2/0000000001").unwrap();
+        assert_eq!(log.len(), other.len());
+        assert_ne!(log, other);
+        fs::remove_dir_all("data-crypt-2").expect("Should have deleted test dir");
+
+        let tree = UrkelTrie::open_with_options("data-crypt", options([7; 32], nodes)).unwrap();
+        assert_eq!(tree.get_root_hash(), root);
+        assert_eq!(tree.get(b"name-3").unwrap(), Some(Vec::from("secret-3")));
+        let mut proof = tree.prove(b"name-3").unwrap();
+        assert_eq!(proof.verify(root, b"name-3"), Ok(Vec::from("secret-3")));
+        drop(tree);
+
+        // The wrong key doesn't get past authentication
+        assert!(UrkelTrie::open_with_options("data-crypt", options([8; 32], nodes)).is_err());
+
+        fs::remove_dir_all("data-crypt").expect("Should have deleted test dir");
+    }
+}
+
+#[test]
+fn test_tree_dedup_values() {
+    use std::fs;
+    use urkel_trie::Options;
+
+    let options = Options {
+        dedup_values: true,
+        ..Options::default()
+    };
+    let shared = vec![7u8; 1000];
+
+    {
+        let mut tree = UrkelTrie::open_with_options("data-dedup", options.clone()).unwrap();
+        for i in 0..50 {
+            tree.set(format!("name-{}", i).as_bytes(), shared.clone())
+                .unwrap();
+        }
+        tree.set(b"other", "value").unwrap();
+        let info = tree.commit().unwrap();
+        assert_eq!((info.values, info.deduplicated), (2, 49));
+        // One copy of the shared value, not 50
+        let size = fs::metadata("data-dedup/0000000001").unwrap().len();
+        assert!(size < 2 * 1000 + 100 * 76);
+        assert_eq!(tree.get(b"name-9").unwrap(), Some(shared.clone()));
+        assert_eq!(tree.get(b"other").unwrap(), Some(Vec::from("value")));
+    }
+
+    // The index survives reopening: new leaves still share the old copy
+    let mut tree = UrkelTrie::open_with_options("data-dedup", options).unwrap();
+    tree.set(b"name-50", shared.clone()).unwrap();
+    let info = tree.commit().unwrap();
+    assert!(info.bytes < 1000);
+    assert_eq!((info.values, info.deduplicated), (0, 1));
+    assert_eq!(tree.get(b"name-50").unwrap(), Some(shared.clone()));
+
+    // Removing some of the leaves doesn't affect the others
+    tree.remove(b"name-0").unwrap();
+    tree.commit().unwrap();
+    assert_eq!(tree.get(b"name-0").unwrap(), None);
+    assert_eq!(tree.get(b"name-1").unwrap(), Some(shared));
+    drop(tree);
+
+    fs::remove_dir_all("data-dedup").expect("Should have deleted test dir");
+}
+
+#[test]
+fn test_tree_inline_values() {
+    use std::fs;
+    use urkel_trie::Options;
+
+    // Nothing to compress, in case values are
+    let large: Vec<u8> = (0..100u32).map(|i| (i * 37 % 251) as u8).collect();
+    {
+        let mut tree = UrkelTrie::new("data-inline");
+        tree.set(b"small", "tiny").unwrap();
+        tree.set(b"large", large.clone()).unwrap();
+        tree.commit().unwrap();
+    }
+
+    // With only two leaves, both are as deep. A small value comes with its
+    // leaf; a large one is another read.
+    let options = Options {
+        cache_size: 1024,
+        cache_values: true,
+        ..Options::default()
+    };
+    let misses = |key: &[u8], value: Vec<u8>| {
+        let tree = UrkelTrie::open_with_options("data-inline", options.clone()).unwrap();
+        assert_eq!(tree.get(key).unwrap(), Some(value));
+        tree.cache_stats().misses
+    };
+    assert_eq!(
+        misses(b"large", large.clone()),
+        misses(b"small", Vec::from("tiny")) + 1
+    );
+
+    // Without cache_values the leaf is cached without its value, which is
+    // read again from the leaf's record
+    let options = Options {
+        cache_size: 1024,
+        ..Options::default()
+    };
+    let tree = UrkelTrie::open_with_options("data-inline", options).unwrap();
+    for _ in 0..2 {
+        assert_eq!(tree.get(b"small").unwrap(), Some(Vec::from("tiny")));
+    }
+    drop(tree);
+
+    let tree = UrkelTrie::open("data-inline").unwrap();
+    let mut proof = tree.prove(b"small").unwrap();
+    assert_eq!(
+        proof.verify(tree.get_root_hash(), b"small"),
+        Ok(Vec::from("tiny"))
+    );
+    drop(tree);
+    fs::remove_dir_all("data-inline").expect("Should have deleted test dir");
+
+    // A leaf at the root is flagged in the meta
+    let root = {
+        let mut tree = UrkelTrie::new("data-inline");
+        tree.set(b"small", "tiny").unwrap();
+        tree.commit().unwrap();
+        tree.get_root_hash()
+    };
+    let tree = UrkelTrie::open("data-inline").unwrap();
+    assert_eq!(tree.get_root_hash(), root);
+    assert_eq!(tree.get(b"small").unwrap(), Some(Vec::from("tiny")));
+    drop(tree);
+
+    fs::remove_dir_all("data-inline").expect("Should have deleted test dir");
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_tree_mmap_after_growth() {
+    use std::fs;
+
+    let mut tree = UrkelTrie::new("data-mmap");
+    for i in 0..10 {
+        tree.set(format!("name-{}", i).as_bytes(), format!("value-{}", i))
+            .unwrap();
+    }
+    tree.commit().unwrap();
+    // Maps the log as it is now
+    assert_eq!(tree.get(b"name-1").unwrap(), Some(Vec::from("value-1")));
+    let reader = UrkelTrie::open_read_only("data-mmap").unwrap();
+    assert_eq!(reader.get(b"name-2").unwrap(), Some(Vec::from("value-2")));
+
+    // Grow the file past both maps
+    for i in 10..200 {
+        tree.set(format!("name-{}", i).as_bytes(), format!("value-{}", i))
+            .unwrap();
+    }
+    tree.commit().unwrap();
+
+    // The writer reads the new commit, the reader still reads its own
+    assert_eq!(tree.get(b"name-150").unwrap(), Some(Vec::from("value-150")));
+    assert_eq!(tree.get(b"name-3").unwrap(), Some(Vec::from("value-3")));
+    assert_eq!(reader.get(b"name-4").unwrap(), Some(Vec::from("value-4")));
+    assert_eq!(reader.get(b"name-150").unwrap(), None);
+
+    // A reader opened now maps the grown file
+    let reader = UrkelTrie::open_read_only("data-mmap").unwrap();
+    assert_eq!(
+        reader.get(b"name-199").unwrap(),
+        Some(Vec::from("value-199"))
+    );
+    drop((tree, reader));
+
+    fs::remove_dir_all("data-mmap").expect("Should have deleted test dir");
 }